@@ -5,12 +5,77 @@ use std::io::Write;
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct Config {
     source: Option<String>,
+    on_ssh_auth_failure: Option<String>,
+    auth: Option<SourceAuth>,
+    trusted_public_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SourceAuth {
+    method: Option<String>,
+    token_env: Option<String>,
+    token_file: Option<String>,
+    username: Option<String>,
+    header: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 struct Ssh {
     setup_ssh_key: Option<bool>,
     no_passphrase: Option<bool>,
+    keys: Option<Vec<SshKey>>,
+    hosts: Option<Vec<SshHost>>,
+    known_hosts: Option<Vec<SshKnownHost>>,
+    builtin_known_hosts: Option<bool>,
+    register: Option<SshRegister>,
+    agent: Option<String>,
+    add_keys_to_agent: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SshKey {
+    #[serde(rename = "type")]
+    key_type: Option<String>,
+    bits: Option<u32>,
+    path: Option<String>,
+    comment: Option<String>,
+    passphrase: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SshHost {
+    host: String,
+    hostname: Option<String>,
+    user: Option<String>,
+    port: Option<u16>,
+    identity_file: Option<String>,
+    proxy_jump: Option<String>,
+    options: Option<std::collections::BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SshKnownHost {
+    host: String,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Forge {
+    #[default]
+    Github,
+    Gitlab,
+    #[serde(alias = "forgejo")]
+    Gitea,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SshRegister {
+    forge: Forge,
+    base_url: Option<String>,
+    token_env: Option<String>,
+    token_file: Option<String>,
+    key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -18,6 +83,27 @@ struct Git {
     name: Option<String>,
     email: Option<String>,
     overwrite_existing: Option<bool>,
+    config: Option<toml::Table>,
+    include_if: Option<Vec<GitIncludeIf>>,
+    signing: Option<GitSigning>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct GitSigning {
+    method: String,
+    key: Option<String>,
+    email: Option<String>,
+    sign_commits: Option<bool>,
+    sign_tags: Option<bool>,
+    allowed_signers: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct GitIncludeIf {
+    condition: String,
+    name: Option<String>,
+    email: Option<String>,
+    config: Option<toml::Table>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -28,6 +114,41 @@ struct Repository {
     synchronise: Option<bool>,
     run_once: Option<String>,
     run_everytime: Option<String>,
+    branch: Option<String>,
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    depth: Option<u32>,
+    recurse_submodules: Option<bool>,
+    sparse: Option<Vec<String>>,
+    origin_name: Option<String>,
+    depends_on: Option<Vec<String>>,
+    untracked: Option<String>,
+    commit_message: Option<String>,
+    existing: Option<String>,
+    on_ssh_auth_failure: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct RepositorySettings {
+    jobs: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct RepositorySet {
+    forge: Forge,
+    base_url: Option<String>,
+    org: Option<String>,
+    user: Option<String>,
+    target: String,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    archived: Option<bool>,
+    forks: Option<bool>,
+    protocol: Option<String>,
+    token_env: Option<String>,
+    token_file: Option<String>,
+    update: Option<bool>,
+    synchronise: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -89,12 +210,61 @@ struct Signal {
     install_signal_desktop_ubuntu: Option<bool>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Shell {
+    login_shell: Option<String>,
+    aliases: Option<std::collections::BTreeMap<String, String>>,
+    env: Option<std::collections::BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SystemdUserUnit {
+    name: String,
+    content: Option<String>,
+    file: Option<String>,
+    enable: Option<bool>,
+    running: Option<bool>,
+    restart_on_change: Option<bool>,
+    linger: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Dconf {
+    path: String,
+    content: Option<String>,
+    file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Kde {
+    reconfigure: Option<bool>,
+    #[serde(flatten)]
+    files: std::collections::BTreeMap<String, std::collections::BTreeMap<String, toml::Value>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Font {
+    name: String,
+    url: String,
+    sha256: String,
+    files: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct DefaultApps {
+    web_browser: Option<String>,
+    terminal: Option<String>,
+    mime: Option<std::collections::BTreeMap<String, String>>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 struct Setupfile {
     packages: Option<Packages>,
     ssh: Option<Ssh>,
     git: Option<Git>,
     repositories: Option<Vec<Repository>>,
+    repository_settings: Option<RepositorySettings>,
+    repository_sets: Option<Vec<RepositorySet>>,
     symlink: Option<Symlink>,
     xdg_user_dirs: Option<XdgUserDirs>,
     ubuntu: Option<Ubuntu>,
@@ -102,6 +272,14 @@ struct Setupfile {
     uv: Option<Uv>,
     ghostty: Option<Ghostty>,
     signal: Option<Signal>,
+    shell: Option<Shell>,
+    environment: Option<std::collections::BTreeMap<String, String>>,
+    systemd_user: Option<Vec<SystemdUserUnit>>,
+    gsettings: Option<std::collections::BTreeMap<String, std::collections::BTreeMap<String, toml::Value>>>,
+    dconf: Option<Vec<Dconf>>,
+    kde: Option<Kde>,
+    fonts: Option<Vec<Font>>,
+    default_apps: Option<DefaultApps>,
 }
 
 fn get_home() -> String {
@@ -147,7 +325,7 @@ fn read_config() -> Result<Config, Box<dyn std::error::Error>> {
         return Ok(config);
     }
 
-    Ok(Config{source: None})
+    Ok(Config::default())
 }
 
 fn write_config(config: Config) -> Result<(), Box<dyn std::error::Error>> {
@@ -166,96 +344,215 @@ fn hash_string(input: &str) -> String {
     format!("{:x}", sha256.finalize())
 }
 
-fn get_setup(source: &str) -> Result<Setupfile, Box<dyn std::error::Error>> {
-    use std::io::Read;
-
-    let mut source = source.to_owned();
-    if source.starts_with("git://")
+fn is_git_source(source: &str) -> bool {
+    source.starts_with("git://")
         || source.starts_with("ssh://")
         || source.starts_with("git@")
         || source.ends_with(".git")
-        || source.ends_with(".git/") {
-        let hash = &hash_string(&source)[..16];
+        || source.ends_with(".git/")
+}
+
+/// Hides credentials in a URL (a password or HTTP user in the user info and query parameters), so it can be shown. scp-like SSH
+/// sources (`git@host:path`) and local paths are shown as they are.
+fn redact_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let (authority, path) = rest.split_once('/').map(|(authority, path)| (authority, format!("/{}", path))).unwrap_or((rest, String::new()));
+    let authority = match authority.rsplit_once('@') {
+        // For HTTP the user alone is often a token as well (`https://<token>@github.com/…`)
+        Some((userinfo, host)) if userinfo.contains(':') || scheme.starts_with("http") => format!("***@{}", host),
+        _ => authority.to_string(),
+    };
+    let path = match path.split_once('?') {
+        Some((path, _)) => format!("{}?***", path),
+        None => path,
+    };
+    format!("{}://{}{}", scheme, authority, path)
+}
+
+fn source_request(client: &reqwest::blocking::Client, url: &str, auth: Option<&SourceAuth>) -> Result<reqwest::blocking::RequestBuilder, Box<dyn std::error::Error>> {
+    let request = client.get(url)
+        .header(reqwest::header::USER_AGENT, format!("zgtm/localsetup {}", VERSION));
+    let Some(auth) = auth else {
+        return Ok(request);
+    };
+    let Some(secret) = read_secret(auth.token_env.as_ref(), auth.token_file.as_ref())? else {
+        return Err("No token given for the setupfile source, set token_env or token_file".into());
+    };
+    match auth.method.as_deref().unwrap_or("bearer") {
+        "bearer" => Ok(request.bearer_auth(secret)),
+        "basic" => Ok(request.basic_auth(auth.username.as_deref().ok_or("username is needed for basic auth")?, Some(secret))),
+        "header" => Ok(request.header(auth.header.as_deref().ok_or("header is needed for header auth")?, secret)),
+        method => Err(format!("Unknown auth method '{}', use 'bearer', 'basic' or 'header'", method).into()),
+    }
+}
+
+/// The URL of the signature of a setupfile: `.minisig` appended to the path, before any query or fragment.
+fn signature_url(url: &str) -> String {
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    format!("{}.minisig{}", &url[..end], &url[end..])
+}
+
+/// Refuses a setupfile that is not signed with `trusted_public_key`, if one is configured.
+fn verify_setupfile(config: &Config, content: &str, signature: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(trusted_public_key) = config.trusted_public_key.as_ref() else {
+        return Ok(());
+    };
+    // Both the bare key and the content of a minisign public key file (with the comment line) are fine
+    let trusted_public_key = trusted_public_key.lines().map(str::trim).rfind(|line| !line.is_empty()).unwrap_or_default();
+    let public_key = minisign_verify::PublicKey::from_base64(trusted_public_key)
+        .map_err(|err| format!("Invalid trusted_public_key: {}", err))?;
+    let signature = signature.ok_or("The setupfile is not signed, refusing to apply it")?;
+    let signature = minisign_verify::Signature::decode(signature)
+        .map_err(|err| format!("Invalid setupfile signature, refusing to apply the setupfile: {}", err))?;
+    public_key.verify(content.as_bytes(), &signature, false)
+        .map_err(|_| "The setupfile signature does not match trusted_public_key, refusing to apply it")?;
+    Ok(())
+}
+
+/// Reads a setupfile and its signature (`<filename>.minisig`, if it exists) and verifies it.
+fn read_setupfile(config: &Config, filename: &str) -> Result<Setupfile, Box<dyn std::error::Error>> {
+    let config_toml = std::fs::read_to_string(filename).map_err(|err| format!("Could not open {}: {}", filename, err))?;
+    let signature = std::fs::read_to_string(format!("{}.minisig", filename)).ok();
+    verify_setupfile(config, &config_toml, signature.as_deref())?;
+    let setup: Setupfile = toml::from_str(&config_toml)?;
+    Ok(setup)
+}
+
+fn get_setup(config: &Config) -> Result<Setupfile, Box<dyn std::error::Error>> {
+    let mut source = config.source.clone().ok_or("No config file set")?;
+    // Git sources can name a branch and a directory inside the repository: <url>#<branch>:<path>
+    let (url, fragment) = source.split_once('#').unwrap_or((&source, ""));
+    if is_git_source(url) {
+        let (branch, directory) = fragment.split_once(':').unwrap_or((fragment, ""));
+        let hash = &hash_string(url)[..16];
         let cache_path = get_cache_path();
         let repository_dir = format!("{}/git_checkout/{}", cache_path, hash);
-        if path_exists(&repository_dir) {
-            println!("==============================================================================");
-            let _status = std::process::Command::new("git")
-                .arg("pull")
+        let policy = config.on_ssh_auth_failure.as_deref();
+        let git = |args: &[&str]| -> Result<bool, Box<dyn std::error::Error>> {
+            eprintln!("==============================================================================");
+            let status = std::process::Command::new("git")
+                .args(args)
                 .current_dir(&repository_dir)
-                .status()
-                .expect("failed to execute process");
-            println!("==============================================================================");
-        } else {
-            let _status = std::process::Command::new("mkdir")
-                .arg("-p")
-                .arg(&repository_dir)
+                .stdout(std::io::stderr())
                 .status()?;
+            eprintln!("==============================================================================");
+            Ok(status.success())
+        };
+        if path_exists(&repository_dir) {
+            restore_ssh_remote(url, policy, &repository_dir, "origin", &RepositoryLog{file: None, stderr: true})?;
+            // The cached checkout is shared by all branches, so without a branch go back to the default branch
+            let branch = if branch.is_empty() {
+                let _ = git_output(&repository_dir, &["remote", "set-head", "origin", "--auto"]);
+                let default_branch = git_output(&repository_dir, &["symbolic-ref", "--quiet", "--short", "refs/remotes/origin/HEAD"])?;
+                default_branch.strip_prefix("origin/")
+                    .ok_or(format!("Could not determine the default branch of {}", redact_url(url)))?
+                    .to_string()
+            } else {
+                branch.to_string()
+            };
+            if git_output(&repository_dir, &["symbolic-ref", "--quiet", "--short", "HEAD"])? != branch {
+                if !git(&["fetch", "origin"])? {
+                    return Err(format!("Could not fetch {}", redact_url(url)).into());
+                }
+                if !git(&["checkout", &branch])? {
+                    return Err(format!("Could not check out branch {} of {}", branch, redact_url(url)).into());
+                }
+            }
+            git(&["pull"])?;
+        } else {
+            // The Setupfile is not known yet, so at least make sure cloning from well-known forges does not prompt
+            let _ = ensure_known_hosts(&builtin_known_hosts());
 
-            println!("==============================================================================");
-            let _status = std::process::Command::new("git")
-                .arg("clone")
-                .arg(&source)
-                .arg(&repository_dir)
-                .status()
-                .expect("failed to execute process");
-            println!("==============================================================================");
+            std::fs::create_dir_all(&repository_dir)?;
+
+            let clone = |url: &str| {
+                let mut args = vec!["clone", url, "."];
+                if !branch.is_empty() {
+                    args.push("--branch");
+                    args.push(branch);
+                }
+                git(&args)
+            };
+            let mut cloned = clone(url)?;
+            if !cloned && let Some(retry_url) = ssh_auth_failure_retry_url(url, policy, &RepositoryLog{file: None, stderr: true})? {
+                cloned = clone(&retry_url)?;
+            }
+            if !cloned {
+                // Start from scratch next time instead of pulling in an empty directory
+                let _ = std::fs::remove_dir_all(&repository_dir);
+                return Err(format!("Could not clone {}", redact_url(url)).into());
+            }
         }
-        // TODO: Add handling for failed clone (generate and output key, show user, try again)
 
-        let output = std::process::Command::new("uname")
-            .arg("-n")
-            .output()?;
-        let hostname = &String::from_utf8(output.stdout).unwrap().trim().to_owned();
+        let hostname = get_hostname()?;
 
-        let filename =  format!("{}/{}.toml", repository_dir, hostname);
+        let directory = directory.trim_matches('/');
+        let filename = if directory.is_empty() {
+            format!("{}/{}.toml", repository_dir, hostname)
+        } else {
+            format!("{}/{}/{}.toml", repository_dir, directory, hostname)
+        };
 
-        let mut file = std::fs::File::open(filename)?;
-        let mut config_toml = String::new();
-        file.read_to_string(&mut config_toml)?;
-        let setup: Setupfile = toml::from_str(&config_toml)?;
-        return Ok(setup);
+        read_setupfile(config, &filename)
     }
     else if source.starts_with("http://") || source.starts_with("https://") {
         if source.starts_with("https://github.com/") && source.contains("blob") {
             #[cfg(debug_assertions)]
-            print!("Replacing {} by ", source);
+            eprint!("Replacing {} by ", redact_url(&source));
             source = source.replacen("https://github.com/", "https://raw.githubusercontent.com/", 1).replacen("/blob/", "/refs/heads/", 1).to_owned();
             #[cfg(debug_assertions)]
-            println!("{}", source);
+            eprintln!("{}", redact_url(&source));
         }
 
         let cache_path = get_cache_path();
-        match reqwest::blocking::get(&source).and_then(|r| r.text()) {
+        let client = reqwest::blocking::Client::new();
+        // The raw GitHub URL gets the same credentials, so private repositories work as well
+        // A missing token counts as a failed download, so scheduled runs without it still use the cached copy
+        let download = source_request(&client, &source, config.auth.as_ref()).and_then(|request| {
+            request.send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.text())
+                .map_err(|err| Box::new(err.without_url()) as Box<dyn std::error::Error>)
+        });
+        match download {
             Ok(body) => {
+                let signature = match config.trusted_public_key {
+                    Some(_) => source_request(&client, &signature_url(&source), config.auth.as_ref())?
+                        .send()
+                        .and_then(|r| r.error_for_status())
+                        .and_then(|r| r.text())
+                        .ok(),
+                    None => None,
+                };
+                // Only a verified setupfile replaces the cached copy
+                verify_setupfile(config, &body, signature.as_deref())?;
+
                 std::fs::create_dir_all(&cache_path)?;
-                let mut file = std::fs::File::create(cache_path + "/localsetup.toml")?;
+                let mut file = std::fs::File::create(cache_path.clone() + "/localsetup.toml")?;
                 use std::io::Write;
                 let _ = file.write_all(body.as_bytes());
+                match signature {
+                    Some(signature) => std::fs::write(cache_path + "/localsetup.toml.minisig", signature)?,
+                    None => { let _ = std::fs::remove_file(cache_path + "/localsetup.toml.minisig"); }
+                }
                 let setup: Setupfile = toml::from_str(&body)?;
-                return Ok(setup);
+                Ok(setup)
             }
             Err(err) =>  {
-                println!("Could not get setup file from {}", &source);
-
-                if let Ok(mut file) = std::fs::File::open(cache_path.clone() + "/localsetup.toml") {
-                    let mut config_toml = String::new();
-                    file.read_to_string(&mut config_toml)?;
+                eprintln!("Could not get setup file from {}", redact_url(&source));
 
-                    println!("Using locally cached version at {} instead", cache_path.clone() + "/localsetup.toml");
-                    let setup: Setupfile = toml::from_str(&config_toml)?;
-                    return Ok(setup);
+                if path_exists(&(cache_path.clone() + "/localsetup.toml")) {
+                    eprintln!("Using locally cached version at {} instead ({})", cache_path.clone() + "/localsetup.toml", err);
+                    read_setupfile(config, &(cache_path + "/localsetup.toml"))
                 } else {
-                    return Err(Box::new(err));
+                    Err(err)
                 }
             }
         }
     } else {
-        let mut file = std::fs::File::open(source)?;
-        let mut config_toml = String::new();
-        file.read_to_string(&mut config_toml)?;
-        let setup: Setupfile = toml::from_str(&config_toml)?;
-        return Ok(setup);
+        read_setupfile(config, &source)
     }
 }
 
@@ -263,814 +560,3110 @@ fn path_exists(path: &str) -> bool {
     std::path::Path::new(path).exists()
 }
 
-fn setup_ssh_key(no_passphrase: bool) -> Result<(), Box<dyn std::error::Error>> {
-    print!("Setting up SSH key … ");
-
-    if path_exists(&(get_home() + "/.ssh/id_ed25519.pub")) {
-        println!("Already set up");
-        return Ok(())
-    }
-    println!("");
+/// Whether localsetup may prompt the user. Scheduled runs (see `localsetup schedule`) are never interactive.
+fn is_interactive() -> bool {
+    use std::io::IsTerminal;
+    std::io::stdin().is_terminal() && std::env::var_os("LOCALSETUP_NON_INTERACTIVE").is_none()
+}
 
-    println!("==============================================================================");
-    let _status = if no_passphrase {
-        std::process::Command::new("ssh-keygen")
-            .arg("-t")
-            .arg("ed25519")
-            .arg("-f")
-            .arg(&(get_home() + "/.ssh/id_ed25519"))
-            .arg("-N")
-            .arg("")
-            .status()
-            .expect("failed to execute process")
+fn expand_home(path: &str) -> String {
+    if let Some(path) = path.strip_prefix("~/") {
+        get_home() + "/" + path
     } else {
-        std::process::Command::new("ssh-keygen")
-            .arg("-t")
-            .arg("ed25519")
-            .arg("-f")
-            .arg(&(get_home() + "/.ssh/id_ed25519"))
-            .status()
-            .expect("failed to execute process")
-    };
-    println!("==============================================================================");
-
-    let mut file = std::fs::File::open(&(get_home() + "/.ssh/id_ed25519.pub"))?;
-    let mut public_key = String::new();
-    use std::io::Read;
-    file.read_to_string(&mut public_key)?;
+        path.replace("$HOME", &get_home())
+    }
+}
 
-    println!("=================== YOUR PUBLIC SSH KEY: ====================\n");
-    println!("{}", public_key);
-    println!("-------------------------------------------------------------");
-    println!("Copy this key if you need to access to repositories via SSH.");
-    println!("-------------------------------------------------------------");
-    println!("\nPress enter to continue …");
-    let _ = std::io::stdin().lines().next();
-    Ok(())
+fn command_exists(command: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(std::process::Command::new("which")
+        .arg(command)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?
+        .success())
 }
 
-fn setup_git(git: &Git) -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(git_name) = git.name.as_ref() {
-        let git_name_already_set = std::process::Command::new("git")
-            .arg("config")
-            .arg("--global")
-            .arg("user.name")
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status()?
-            .success();
-        if !git_name_already_set || git.overwrite_existing.unwrap_or_default() {
-                println!("==============================================================================");
-                let _status = std::process::Command::new("git")
-                    .arg("config")
-                    .arg("--global")
-                    .arg("user.name")
-                    .arg(git_name)
-                    .status()?;
-                println!("==============================================================================");
-            }
+/// Writes `content` to `filename` unless the file already has exactly this content. Returns whether the file changed.
+fn write_file_if_changed(filename: &str, content: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    if std::fs::read_to_string(filename).is_ok_and(|existing| existing == content) {
+        return Ok(false);
     }
 
-    if let Some(git_email) = git.email.as_ref() {
-        let git_email_already_set = std::process::Command::new("git")
-            .arg("config")
-            .arg("--global")
-            .arg("user.email")
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status()?
-            .success();
-        if !git_email_already_set || git.overwrite_existing.unwrap_or_default() {
-                println!("==============================================================================");
-                let _status = std::process::Command::new("git")
-                    .arg("config")
-                    .arg("--global")
-                    .arg("user.email")
-                    .arg(git_email)
-                    .status()?;
-                println!("==============================================================================");
-            }
+    if let Some((directory, _)) = filename.rsplit_once("/") && !directory.is_empty() {
+        std::fs::create_dir_all(directory)?;
     }
-
-    Ok(())
+    std::fs::write(filename, content)?;
+    Ok(true)
 }
 
-fn setup_repository(repository: &Repository) -> Result<bool, Box<dyn std::error::Error>> {
-    let target = if let Some(target) = repository.target.strip_prefix("~/") {
-        get_home() + "/" + target
-    } else {
-        repository.target.to_owned()
-    };
+/// Appends `line` to `filename` (creating it if needed) unless the file already contains that line.
+fn ensure_line_in_file(filename: &str, line: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let existing = std::fs::read_to_string(filename).unwrap_or_default();
+    if existing.lines().any(|l| l.trim() == line.trim()) {
+        return Ok(false);
+    }
 
-    if path_exists(&target) {
-        println!("Existing repository: {} -> {}", repository.source, repository.target);
-        return Ok(false)
+    let mut content = existing;
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
     }
+    content.push_str(line);
+    content.push('\n');
+    write_file_if_changed(filename, &content)
+}
 
-    println!("Creating repository: {} -> {}", repository.source, repository.target);
-    let (mut base, dir) = target.rsplit_once("/").expect("Invalid directory");
-    if dir == "" {
-        (base, _)  = base.rsplit_once("/").expect("Invalid directory");
+/// Matches `text` against a pattern where `*` matches any number of characters and `?` matches one character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
     }
+    pattern[p..].iter().all(|&c| c == '*')
+}
 
-    if !path_exists(base) {
-        std::fs::create_dir_all(base)?;
+fn find_files(directory: &std::path::Path) -> Result<Vec<std::path::PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.append(&mut find_files(&path)?);
+        } else {
+            files.push(path);
+        }
     }
+    Ok(files)
+}
 
-    println!("==============================================================================");
-    let _status = std::process::Command::new("git")
-        .arg("clone")
-        .arg(&repository.source)
-        .arg(&target)
-        .status()
-        .expect("failed to execute process");
-    println!("==============================================================================");
+fn shell_single_quote(value: &str) -> String {
+    format!("'{}'", value.replace("'", "'\\''"))
+}
 
-    Ok(true)
+/// Quotes a value in double quotes, so `$VARIABLE` references are still expanded by the shell.
+fn shell_double_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace("\\", "\\\\").replace("\"", "\\\"").replace("`", "\\`"))
 }
 
-fn update_repository(repository: &Repository) -> Result<(), Box<dyn std::error::Error>> {
-    let target = if let Some(target) = repository.target.strip_prefix("~/") {
-        get_home() + "/" + target
-    } else {
-        repository.target.to_owned()
-    };
+fn is_valid_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-    if !path_exists(&target) {
-        println!("Error! Repository: {} -> {} does not exist!", repository.source, repository.target);
-        // TODO: Fail!
-    }
+fn get_hostname() -> Result<String, Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("uname")
+        .arg("-n")
+        .output()?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
 
-    println!("Updating repository: {} -> {}", repository.source, repository.target);
+fn get_date(format: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("date")
+        .arg(format!("+{}", format))
+        .output()?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
 
-    println!("==============================================================================");
-    let _status = std::process::Command::new("git")
-        .arg("pull")
-        .current_dir(target)
-        .status()
-        .expect("failed to execute process");
-    println!("==============================================================================");
+/// The SSH keys to set up: the configured ones, or (if none are given, also with `keys = []`) a single ed25519 key at
+/// the default location.
+fn ssh_keys(ssh: Option<&Ssh>) -> Vec<SshKey> {
+    ssh.and_then(|ssh| ssh.keys.clone()).filter(|keys| !keys.is_empty()).unwrap_or(vec![SshKey::default()])
+}
+
+/// The path of the first SSH key, which is used for registering with a forge and for signing.
+fn primary_ssh_key_path(ssh: Option<&Ssh>) -> String {
+    ssh_keys(ssh).first().map(ssh_key_path).unwrap_or_default()
+}
+
+fn ssh_key_path(key: &SshKey) -> String {
+    match key.path.as_ref() {
+        Some(path) => expand_home(path),
+        None => format!("{}/.ssh/id_{}", get_home(), key.key_type.as_deref().unwrap_or("ed25519")),
+    }
+}
 
+fn set_permissions(path: &str, mode: u32) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    if path_exists(path) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
     Ok(())
 }
 
-fn synchronise_repository(repository: &Repository) -> Result<(), Box<dyn std::error::Error>> {
-    let target = if let Some(target) = repository.target.strip_prefix("~/") {
-        get_home() + "/" + target
-    } else {
-        repository.target.to_owned()
+fn setup_ssh_key(key: &SshKey, no_passphrase: bool, wait_for_user: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let key_type = key.key_type.as_deref().unwrap_or("ed25519");
+    let key_path = ssh_key_path(key);
+    print!("Setting up SSH key {} … ", key_path);
+
+    let ssh_dir = get_home() + "/.ssh";
+    std::fs::create_dir_all(&ssh_dir)?;
+    set_permissions(&ssh_dir, 0o700)?;
+
+    // Never overwrite existing keys, only make sure they have sensible permissions
+    if path_exists(&key_path) || path_exists(&(key_path.clone() + ".pub")) {
+        set_permissions(&key_path, 0o600)?;
+        set_permissions(&(key_path.clone() + ".pub"), 0o644)?;
+        println!("Already set up");
+        return Ok(())
+    }
+    println!();
+
+    let no_passphrase = match key.passphrase.as_deref() {
+        Some("none") => true,
+        Some("ask") => false,
+        Some(policy) => {
+            println!("Error! Unknown passphrase policy '{}', use 'ask' or 'none'", policy);
+            return Ok(())
+        }
+        None => no_passphrase,
     };
+    if !no_passphrase && !is_interactive() {
+        println!("Skipping, a passphrase can only be entered when running interactively");
+        return Ok(())
+    }
 
-    if !path_exists(&target) {
-        println!("Error! Repository: {} -> {} does not exist!", repository.source, repository.target);
-        // TODO: Fail!
+    if let Some((key_dir, _)) = key_path.rsplit_once("/") {
+        std::fs::create_dir_all(key_dir)?;
     }
 
-    println!("Synchronising repository: {} -> {}", repository.source, repository.target);
+    let comment = match key.comment.as_ref() {
+        Some(comment) => comment.clone(),
+        None => format!("{}@{}", get_username()?, get_hostname()?),
+    };
 
     println!("==============================================================================");
-    let _status = std::process::Command::new("git")
-        .arg("commit")
-        .arg("-am")
-        .arg("autocommit")
-        .current_dir(&target)
-        .status()
-        .expect("failed to execute process");
+    let mut command = std::process::Command::new("ssh-keygen");
+    command.arg("-t")
+        .arg(key_type)
+        .arg("-f")
+        .arg(&key_path)
+        .arg("-C")
+        .arg(&comment);
+    if let Some(bits) = key.bits {
+        command.arg("-b").arg(bits.to_string());
+    }
+    if no_passphrase {
+        command.arg("-N").arg("");
+    }
+    let status = command.status().expect("failed to execute process");
     println!("==============================================================================");
+    if !status.success() {
+        println!("Error! Could not create SSH key {}", key_path);
+        return Ok(())
+    }
 
-    println!("==============================================================================");
-    let _status = std::process::Command::new("git")
-        .arg("pull")
-        .arg("-r")
-        .current_dir(&target)
-        .status()
-        .expect("failed to execute process");
-    println!("==============================================================================");
+    set_permissions(&key_path, 0o600)?;
+    set_permissions(&(key_path.clone() + ".pub"), 0o644)?;
 
-    println!("==============================================================================");
-    let _status = std::process::Command::new("git")
-        .arg("push")
-        .current_dir(&target)
-        .status()
-        .expect("failed to execute process");
-    println!("==============================================================================");
+    let mut file = std::fs::File::open(key_path + ".pub")?;
+    let mut public_key = String::new();
+    use std::io::Read;
+    file.read_to_string(&mut public_key)?;
 
+    println!("=================== YOUR PUBLIC SSH KEY: ====================\n");
+    println!("{}", public_key);
+    println!("-------------------------------------------------------------");
+    println!("Copy this key if you need to access to repositories via SSH.");
+    println!("-------------------------------------------------------------");
+    if wait_for_user && is_interactive() {
+        println!("\nPress enter to continue …");
+        let _ = std::io::stdin().lines().next();
+    }
     Ok(())
 }
 
-fn install_packages(packages: Vec<String>, assume_yes: bool) -> Result<(), Box<dyn std::error::Error>> {
-    print!("Installing packages … ");
-    if packages.len() > 0 {
-        println!("");
-        println!("==============================================================================");
-        let _status = if assume_yes {
-            std::process::Command::new("sudo")
-                .arg("apt")
-                .arg("install")
-                .arg("--yes")
-                .args(packages)
-                .status()
-                .expect("failed to execute process")
-        } else {
-            std::process::Command::new("sudo")
-                .arg("apt")
-                .arg("install")
-                .args(packages)
-                .status()
-                .expect("failed to execute process")
-        };
+const SSH_CONFIG_INCLUDE_LINE: &str = "Include config.d/localsetup.conf";
+
+fn setup_ssh_config(ssh: &Ssh) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Setting up SSH config … ");
+
+    let mut content = MANAGED_FILE_HEADER.to_string();
+    let mut errors = Vec::new();
+    for host in ssh.hosts.iter().flatten() {
+        let mut options = vec![
+            ("HostName", host.hostname.clone()),
+            ("User", host.user.clone()),
+            ("Port", host.port.map(|port| port.to_string())),
+            ("IdentityFile", host.identity_file.clone()),
+            ("ProxyJump", host.proxy_jump.clone()),
+        ].into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key.to_string(), value)))
+            .collect::<Vec<(String, String)>>();
+        options.extend(host.options.clone().unwrap_or_default());
+
+        if host.host.trim().is_empty() || host.host.contains('\n') || options.iter().any(|(key, value)| key.contains(char::is_whitespace) || value.contains('\n')) {
+            errors.push(format!("Invalid SSH host entry '{}'", host.host));
+            continue;
+        }
 
-        println!("==============================================================================");
-    } else {
-        println!("No packages to install");
+        content += &format!("\nHost {}\n", host.host);
+        for (key, value) in options {
+            content += &format!("    {} {}\n", key, value);
+        }
     }
 
-    Ok(())
-}
+    if ssh.add_keys_to_agent.unwrap_or_default() {
+        content += "\nHost *\n    AddKeysToAgent yes\n";
+    }
 
-fn remove_packages(packages: Vec<String>, assume_yes: bool) -> Result<(), Box<dyn std::error::Error>> {
-    print!("Removing packages … ");
-    if packages.len() > 0 {
-        println!("");
-        println!("==============================================================================");
-        let _status = if assume_yes {
-            std::process::Command::new("sudo")
-                .arg("apt")
-                .arg("remove")
-                .arg("--yes")
-                .args(packages)
-                .status()?
-        } else {
-            std::process::Command::new("sudo")
-                .arg("apt")
-                .arg("remove")
-                .args(packages)
-                .status()?
-        };
+    let ssh_dir = get_home() + "/.ssh";
+    let managed_config = ssh_dir.clone() + "/config.d/localsetup.conf";
+    let mut changed = write_file_if_changed(&managed_config, &content)?;
+    set_permissions(&managed_config, 0o600)?;
+
+    // The Include needs to come before any Host block, otherwise it would only apply to that host
+    let config = ssh_dir + "/config";
+    let existing = std::fs::read_to_string(&config).unwrap_or_default();
+    if !existing.lines().any(|line| line.trim() == SSH_CONFIG_INCLUDE_LINE) {
+        changed |= write_file_if_changed(&config, &format!("{}\n\n{}", SSH_CONFIG_INCLUDE_LINE, existing))?;
+        set_permissions(&config, 0o600)?;
+    }
 
-        println!("==============================================================================");
+    if changed {
+        println!("done");
     } else {
-        println!("No packages to remove");
+        println!("Already set up");
+    }
+
+    for error in errors {
+        println!("Error! {}", error);
     }
 
     Ok(())
 }
 
+const BUILTIN_KNOWN_HOSTS: [(&str, &str); 3] = [
+    ("github.com", "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl"),
+    ("gitlab.com", "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAfuCHKVTjquxvt6CM6tdG4SLp1Btn/nOeHHE5UOzRdf"),
+    ("codeberg.org", "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIIVIC02vnjFyL+I4RHfvIGNtOgJMe769VTF1VR4EB3ZB"),
+];
 
-fn set_xdg_user_dirs(xdg_user_dirs: &XdgUserDirs) -> Result<(), Box<dyn std::error::Error>> {
-    let move_existing = xdg_user_dirs.move_existing.unwrap_or(false);
-    let home = get_home();
-    let dirs = [("DESKTOP", &xdg_user_dirs.desktop),
-                ("DOWNLOAD", &xdg_user_dirs.download),
-                ("MUSIC", &xdg_user_dirs.music),
-                ("PICTURES", &xdg_user_dirs.pictures),
-                ("PUBLICSHARE", &xdg_user_dirs.publicshare),
-                ("TEMPLATES", &xdg_user_dirs.templates),
-                ("VIDEOS", &xdg_user_dirs.videos)];
-
-    for dir in dirs {
-        if let Some(new_location) = dir.1 {
-            let id = dir.0;
-
-            let output = std::process::Command::new("xdg-user-dir")
-                .arg(&id)
-                .output()?;
-            let current_location = &String::from_utf8(output.stdout).unwrap().trim().to_owned();
-
-            let new_location_absolute = new_location.replace("$HOME", &home);
-            // Current location might or might not be already absolute, but here we need to make sure
-            let current_location_absolute = current_location.replace("$HOME", &home);
-
-            #[cfg(debug_assertions)]
-            println!("'{}', '{}'", current_location_absolute, new_location_absolute);
+fn builtin_known_hosts() -> Vec<SshKnownHost> {
+    BUILTIN_KNOWN_HOSTS.iter()
+        .map(|(host, key)| SshKnownHost{host: host.to_string(), key: key.to_string()})
+        .collect()
+}
 
-            if current_location_absolute == new_location_absolute {
-                println!("xdg-user-dir {} already at location {}", &id, &new_location);
-                continue;
-            }
-            println!("Setting xdg-user-dir {} to location {}", &id, &new_location);
+/// Adds pinned host keys to `~/.ssh/known_hosts` if missing. Returns the number of added keys and the hosts whose
+/// known key does not match the pinned one.
+fn ensure_known_hosts(known_hosts: &[SshKnownHost]) -> Result<(usize, Vec<String>), Box<dyn std::error::Error>> {
+    let ssh_dir = get_home() + "/.ssh";
+    let known_hosts_file = ssh_dir.clone() + "/known_hosts";
+    let mut added = 0;
+    let mut mismatches = Vec::new();
+
+    for known_host in known_hosts {
+        let mut key_fields = known_host.key.split_whitespace();
+        let (Some(key_type), Some(key)) = (key_fields.next(), key_fields.next()) else {
+            mismatches.push(format!("{} (invalid pinned key '{}')", known_host.host, known_host.key));
+            continue;
+        };
 
-            let _status = std::process::Command::new("xdg-user-dirs-update")
-                .arg("--set")
-                .arg(&id)
-                .arg(&new_location_absolute)
-                .status()?;
+        // ssh-keygen also finds hashed entries, so look up by the first host of the pattern list
+        let lookup_host = known_host.host.split(',').next().unwrap_or_default();
+        let output = std::process::Command::new("ssh-keygen")
+            .arg("-F")
+            .arg(lookup_host)
+            .arg("-f")
+            .arg(&known_hosts_file)
+            .stderr(std::process::Stdio::null())
+            .output()?;
+        let existing = String::from_utf8(output.stdout)?;
+        let existing_keys = existing.lines()
+            .filter(|line| !line.starts_with('#') && !line.starts_with('@'))
+            .filter_map(|line| {
+                let fields = line.split_whitespace().collect::<Vec<&str>>();
+                (fields.len() >= 3).then(|| (fields[1].to_string(), fields[2].to_string()))
+            })
+            .collect::<Vec<(String, String)>>();
+
+        if existing_keys.iter().any(|(existing_type, existing_key)| existing_type == key_type && existing_key == key) {
+            continue;
+        }
+        if existing_keys.iter().any(|(existing_type, _)| existing_type == key_type) {
+            mismatches.push(known_host.host.clone());
+            continue;
+        }
 
-            if move_existing {
-                let current_location_absolute = current_location.replace("$HOME", &home);
-                print!("Moving xdg-user-dir {} from current location {} to new location {} … ", &id, &current_location_absolute, &new_location_absolute);
+        std::fs::create_dir_all(&ssh_dir)?;
+        set_permissions(&ssh_dir, 0o700)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&known_hosts_file)?;
+        writeln!(file, "{} {} {}", known_host.host, key_type, key)?;
+        added += 1;
+    }
 
-                let (new_location_absolute_base, _) = new_location_absolute.trim_end_matches('/').rsplit_once("/").expect("Invalid directory");
-                let _status = std::process::Command::new("mkdir")
-                    .arg("-p")
-                    .arg(&new_location_absolute_base)
-                    .status()?;
+    Ok((added, mismatches))
+}
 
-                let status = std::process::Command::new("mv")
-                    .arg(&current_location_absolute)
-                    .arg(&new_location_absolute)
-                    .status()?;
+fn setup_known_hosts(ssh: Option<&Ssh>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut known_hosts = Vec::new();
+    if ssh.and_then(|ssh| ssh.builtin_known_hosts).unwrap_or(true) {
+        known_hosts.extend(builtin_known_hosts());
+    }
+    known_hosts.extend(ssh.and_then(|ssh| ssh.known_hosts.clone()).unwrap_or_default());
+    if known_hosts.is_empty() {
+        return Ok(());
+    }
 
-                if status.success() {
-                    println!("Ok");
-                } else {
-                    println!("ERROR\nError moving directory. Does the new directory already exist?");
-                }
-            } else {
-                print!("Creating xdg-user-dir {} at to new location {} …", &id, &new_location_absolute);
+    print!("Setting up SSH known hosts … ");
+    let (added, mismatches) = ensure_known_hosts(&known_hosts)?;
+    if added > 0 {
+        println!("added {} host keys", added);
+    } else {
+        println!("Already set up");
+    }
 
-                let _status = std::process::Command::new("mkdir")
-                    .arg("-p")
-                    .arg(&new_location_absolute)
-                    .status()?;
-            }
-        }
+    for host in mismatches {
+        println!("Error! The host key for {} in ~/.ssh/known_hosts does not match the pinned key. Not changing it, please check!", host);
     }
 
     Ok(())
 }
 
-fn package_installed(package: &str) -> Result<bool, Box<dyn std::error::Error>> {
-    let status = std::process::Command::new("dpkg")
-        .arg("-s")
-        .arg(package)
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .status()?;
+/// Reads a secret (like an API token) from an environment variable or a file, without ever printing it.
+fn read_secret(env: Option<&String>, file: Option<&String>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if let Some(env) = env && let Ok(secret) = std::env::var(env) && !secret.trim().is_empty() {
+        return Ok(Some(secret.trim().to_string()));
+    }
+    if let Some(file) = file {
+        return Ok(Some(std::fs::read_to_string(expand_home(file))?.trim().to_string()));
+    }
+    Ok(None)
+}
 
-    // Note, if a package is no longer installed, but the config files are still present, we will recognize this as
-    // installed (for dpkg its: "Status: deinstall ok config-files"). For now, we're okay with that!
-    //
-    // Also, if a package is marked as "hold" we will recognize this as installed. TODO: fix that!
-    return Ok(status.success())
+fn forge_api_url(forge: Forge, base_url: Option<&String>) -> Result<String, Box<dyn std::error::Error>> {
+    let base_url = base_url.map(|url| url.trim_end_matches('/').to_string());
+    match forge {
+        Forge::Github => Ok(base_url.unwrap_or("https://api.github.com".to_string())),
+        Forge::Gitlab => Ok(base_url.unwrap_or("https://gitlab.com".to_string()) + "/api/v4"),
+        Forge::Gitea => match base_url {
+            Some(base_url) => Ok(base_url + "/api/v1"),
+            None => Err("base_url is needed for Gitea and Forgejo".into()),
+        },
+    }
 }
 
-const NOSNAPD_FILENAME: &str = "/etc/apt/preferences.d/nosnap.pref";
-const NOSNAPD_FILE_CONTENT: &str = "
-# To prevent repository packages from triggering the installation of snap,
-# this file forbids snapd from being installed by APT.
+fn forge_request(client: &reqwest::blocking::Client, method: reqwest::Method, url: &str, forge: Forge, token: Option<&String>) -> reqwest::blocking::RequestBuilder {
+    let request = client.request(method, url)
+        .header(reqwest::header::USER_AGENT, format!("zgtm/localsetup {}", VERSION));
+    let Some(token) = token else {
+        return request;
+    };
+    match forge {
+        Forge::Github => request.bearer_auth(token).header(reqwest::header::ACCEPT, "application/vnd.github+json"),
+        Forge::Gitlab => request.header("PRIVATE-TOKEN", token),
+        Forge::Gitea => request.header(reqwest::header::AUTHORIZATION, format!("token {}", token)),
+    }
+}
 
-Package: snapd
-Pin: release a=*
-Pin-Priority: -10
-";
+fn register_ssh_key(register: &SshRegister, default_key_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let public_key_path = register.key.as_ref()
+        .map(|path| expand_home(path))
+        .unwrap_or(default_key_path.to_string() + ".pub");
+    print!("Registering SSH key {} with {:?} … ", public_key_path, register.forge);
 
-const FIREFOX_NOSNAP_FILENAME: &str = "/etc/apt/preferences.d/firefox-nosnap.pref";
-const FIREFOX_NOSNAP_FILE_CONTENT: &str = "
-Package: firefox*
-Pin: release o=Ubuntu*
-Pin-Priority: -1
-";
+    let Ok(public_key) = std::fs::read_to_string(&public_key_path) else {
+        println!("ERROR\nPublic key {} does not exist", public_key_path);
+        return Ok(());
+    };
+    // Forges store the key without the comment, so only compare type and key
+    let public_key = public_key.split_whitespace().take(2).collect::<Vec<&str>>().join(" ");
 
-const THUNDERBIRD_NOSNAP_FILENAME: &str = "/etc/apt/preferences.d/thunderbird-nosnap.pref";
-const THUNDERBIRD_NOSNAP_FILE_CONTENT: &str = "
-Package: thunderbird*
-Pin: release o=Ubuntu*
-Pin-Priority: -1
-";
+    let Some(token) = read_secret(register.token_env.as_ref(), register.token_file.as_ref())? else {
+        println!("ERROR\nNo token given, set token_env or token_file");
+        return Ok(());
+    };
+    let api_url = forge_api_url(register.forge, register.base_url.as_ref())?;
+    let client = reqwest::blocking::Client::new();
 
-const FIREFOX_PPA_FILENAME: &str = "/etc/apt/preferences.d/firefox-ppa.pref";
-const FIREFOX_PPA_FILE_CONTENT: &str = "
-Package: firefox*
-Pin: release o=LP-PPA-mozillateam
-Pin-Priority: 501
-";
+    #[derive(Deserialize)]
+    struct ForgeKey {
+        key: String,
+    }
+    let per_page = 50;
+    for page in 1.. {
+        // Gitea and Forgejo call the page size limit
+        let url = format!("{}/user/keys?per_page={1}&limit={1}&page={2}", api_url, per_page, page);
+        let keys = forge_request(&client, reqwest::Method::GET, &url, register.forge, Some(&token))
+            .send()?
+            .error_for_status()?
+            .json::<Vec<ForgeKey>>()?;
+        if keys.iter().any(|key| key.key.split_whitespace().take(2).collect::<Vec<&str>>().join(" ") == public_key) {
+            println!("Already registered");
+            return Ok(());
+        }
+        if keys.len() < per_page {
+            break;
+        }
+    }
 
-const THUNDERBIRD_PPA_FILENAME: &str = "/etc/apt/preferences.d/thunderbird-ppa.pref";
-const THUNDERBIRD_PPA_FILE_CONTENT: &str = "
-Package: thunderbird*
-Pin: release o=LP-PPA-mozillateam
-Pin-Priority: 501
-";
+    let title = format!("{} {}", get_hostname()?, get_date("%F")?);
 
-const FIREFOX_MOZILLA_REPOSITORY_FILENAME: &str = "/etc/apt/sources.list.d/mozilla.sources";
-const FIREFOX_MOZILLA_REPOSITORY_FILE_CONTENT: &str = "
-Types: deb
-URIs: https://packages.mozilla.org/apt
-Suites: mozilla
-Components: main
-Signed-By: /etc/apt/keyrings/packages.mozilla.org.asc
-";
+    forge_request(&client, reqwest::Method::POST, &format!("{}/user/keys", api_url), register.forge, Some(&token))
+        .json(&serde_json::json!({"title": title, "key": public_key}))
+        .send()?
+        .error_for_status()?;
+    println!("done");
 
-const FIREFOX_MOZILLA_FILENAME: &str = "/etc/apt/preferences.d/mozilla";
-const FIREFOX_MOZILLA_FILE_CONTENT: &str = "
-Package: *
-Pin: origin packages.mozilla.org
-Pin-Priority: 1000
-";
+    Ok(())
+}
 
-fn create_file_with_content_if_not_exists_root(filename: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
-    if path_exists(filename) {
-        return Ok(());
+/// An ssh-agent started by localsetup for the duration of the run.
+struct SpawnedSshAgent {
+    pid: String,
+}
+
+impl Drop for SpawnedSshAgent {
+    fn drop(&mut self) {
+        let _ = std::process::Command::new("kill")
+            .arg(&self.pid)
+            .status();
     }
+}
 
-    let (directory, basename) = filename.rsplit_once("/").unwrap();
-    let cache_path = get_cache_path();
+fn ssh_agent_socket_usable(socket: &str) -> bool {
+    // ssh-add -l exits with 1 if the agent has no keys and with 2 if it cannot connect to the agent
+    path_exists(socket) && std::process::Command::new("ssh-add")
+        .arg("-l")
+        .env("SSH_AUTH_SOCK", socket)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.code() == Some(0) || status.code() == Some(1))
+}
 
-    std::fs::create_dir_all(cache_path.clone())?;
-    let mut file = std::fs::File::create(cache_path.clone() + "/" + basename)?;
+fn find_ssh_agent_socket(agent: &str) -> Option<String> {
+    let runtime_dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_default();
+    let candidates = match agent {
+        "auto" => vec![
+            std::env::var("SSH_AUTH_SOCK").unwrap_or_default(),
+            runtime_dir.clone() + "/ssh-agent.socket",
+            runtime_dir.clone() + "/gcr/ssh",
+            runtime_dir + "/keyring/ssh",
+        ],
+        "systemd" => {
+            let _ = std::process::Command::new("systemctl")
+                .arg("--user")
+                .arg("start")
+                .arg("ssh-agent.socket")
+                .stderr(std::process::Stdio::null())
+                .status();
+            vec![runtime_dir + "/ssh-agent.socket"]
+        }
+        "gnome-keyring" => vec![runtime_dir.clone() + "/gcr/ssh", runtime_dir + "/keyring/ssh"],
+        _ => vec![],
+    };
+    candidates.into_iter().find(|socket| !socket.is_empty() && ssh_agent_socket_usable(socket))
+}
 
-    use std::io::Write;
-    file.write_all(content.as_bytes())?;
+fn spawn_ssh_agent() -> Result<(String, SpawnedSshAgent), Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("ssh-agent")
+        .arg("-s")
+        .output()?;
+    let output = String::from_utf8(output.stdout)?;
+
+    // Output looks like: SSH_AUTH_SOCK=/tmp/ssh-XXXXXX/agent.123; export SSH_AUTH_SOCK;
+    let variable = |name: &str| output.lines()
+        .find_map(|line| line.strip_prefix(&format!("{}=", name)))
+        .and_then(|value| value.split(';').next())
+        .map(str::to_owned);
+    match (variable("SSH_AUTH_SOCK"), variable("SSH_AGENT_PID")) {
+        (Some(socket), Some(pid)) => Ok((socket, SpawnedSshAgent{pid})),
+        _ => Err("Could not start ssh-agent".into()),
+    }
+}
 
-    let _status = std::process::Command::new("mkdir")
-                .arg("-p")
-                .arg(directory)
-                .status()?;
+fn add_key_to_ssh_agent(key_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Adding SSH key {} to ssh-agent … ", key_path);
+    if !path_exists(key_path) {
+        println!("Skipping, key does not exist");
+        return Ok(());
+    }
 
-    let _status = std::process::Command::new("sudo")
-                .arg("cp")
-                .arg(cache_path + "/" + basename)
-                .arg(filename)
-                .status()?;
+    let output = std::process::Command::new("ssh-keygen")
+        .arg("-lf")
+        .arg(key_path)
+        .output()?;
+    let fingerprint = String::from_utf8(output.stdout)?.split_whitespace().nth(1).unwrap_or_default().to_string();
+    let output = std::process::Command::new("ssh-add")
+        .arg("-l")
+        .output()?;
+    if !fingerprint.is_empty() && String::from_utf8(output.stdout)?.contains(&fingerprint) {
+        println!("Already added");
+        return Ok(());
+    }
+
+    let has_passphrase = !std::process::Command::new("ssh-keygen")
+        .arg("-y")
+        .arg("-P")
+        .arg("")
+        .arg("-f")
+        .arg(key_path)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?
+        .success();
+    if has_passphrase && !is_interactive() {
+        println!("Skipping, the passphrase can only be entered when running interactively");
+        return Ok(());
+    }
 
+    println!();
+    println!("==============================================================================");
+    let _status = std::process::Command::new("ssh-add")
+        .arg(key_path)
+        .status()?;
+    println!("==============================================================================");
     Ok(())
 }
 
-enum PpaOrMozilla {
-    Ppa,
-    Mozilla,
-}
-
-fn ubuntu_remove_snap_and_install_firefox_ppa_or_mozilla(ppa_or_mozilla: PpaOrMozilla, assume_yes: bool) -> Result<(), Box<dyn std::error::Error>> {
-    print!("Removing snap … ");
-    if package_installed("snapd")? {
-        println!("");
-
-        if !assume_yes {
-            println!("Removing snap and switching from snap-installed firefox and thundebird to PPA\nfirefox and thundebird will remove all bookmarks, setting, emails, and\neverything else. Are you sure you want that? [y/n]");
-            loop {
-                let input_line = std::io::stdin().lines().next().unwrap()?;
-                if input_line == "n" {
-                    return Ok(())
-                }
-                if input_line == "y" {
-                    break;
-                }
-                println!("Please answer 'y' for yes or 'n' for no. Should all bookmarks, setting, emails,\nand everything else be deleted? [y/n]");
-            }
+/// Makes sure an ssh-agent is available for all following git commands and adds the SSH keys to it. If an agent had
+/// to be spawned, it is stopped again when the returned value is dropped.
+fn setup_ssh_agent(agent: &str, keys: &[SshKey]) -> Result<Option<SpawnedSshAgent>, Box<dyn std::error::Error>> {
+    print!("Setting up ssh-agent … ");
+
+    let mut spawned_agent = None;
+    let socket = match find_ssh_agent_socket(agent) {
+        Some(socket) => socket,
+        None if agent == "auto" || agent == "spawn" => {
+            let (socket, spawned) = spawn_ssh_agent()?;
+            spawned_agent = Some(spawned);
+            socket
         }
+        None => {
+            println!("ERROR\nNo ssh-agent found for '{}', use 'auto', 'systemd', 'gnome-keyring' or 'spawn'", agent);
+            return Ok(None);
+        }
+    };
+    println!("using {}", socket);
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("sudo")
-                .arg("apt")
-                .arg("purge")
-                .arg("--yes")
-                .arg("snapd")
-                .status()?;
-        println!("==============================================================================");
-    } else {
-        println!("snap already removed");
+    // SAFETY: No other threads are running at this point (repositories are not being set up yet), so nothing can read
+    // the environment concurrently
+    unsafe {
+        std::env::set_var("SSH_AUTH_SOCK", &socket);
     }
 
-    print!("Ensuring snap will never be installed again … ");
-    if path_exists(NOSNAPD_FILENAME) && path_exists(FIREFOX_NOSNAP_FILENAME) && path_exists(THUNDERBIRD_NOSNAP_FILENAME) {
-        println!("already safe");
-    } else {
-        create_file_with_content_if_not_exists_root(NOSNAPD_FILENAME, NOSNAPD_FILE_CONTENT)?;
-        create_file_with_content_if_not_exists_root(FIREFOX_NOSNAP_FILENAME, FIREFOX_NOSNAP_FILE_CONTENT)?;
-        create_file_with_content_if_not_exists_root(THUNDERBIRD_NOSNAP_FILENAME, THUNDERBIRD_NOSNAP_FILE_CONTENT)?;
-        println!("done");
+    for key in keys {
+        add_key_to_ssh_agent(&ssh_key_path(key))?;
     }
 
-    match ppa_or_mozilla {
-        PpaOrMozilla::Ppa => {
-            print!("Installing Firefox from PPA … ");
-            if !package_installed("firefox")? {
-                println!("");
+    Ok(spawned_agent)
+}
+
+fn setup_git(git: &Git) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(git_name) = git.name.as_ref() {
+        let git_name_already_set = std::process::Command::new("git")
+            .arg("config")
+            .arg("--global")
+            .arg("user.name")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()?
+            .success();
+        if !git_name_already_set || git.overwrite_existing.unwrap_or_default() {
                 println!("==============================================================================");
-                let _status = std::process::Command::new("sudo")
-                    .arg("add-apt-repository")
-                    .arg("ppa:mozillateam/ppa")
+                let _status = std::process::Command::new("git")
+                    .arg("config")
+                    .arg("--global")
+                    .arg("user.name")
+                    .arg(git_name)
                     .status()?;
                 println!("==============================================================================");
+            }
+    }
 
+    if let Some(git_email) = git.email.as_ref() {
+        let git_email_already_set = std::process::Command::new("git")
+            .arg("config")
+            .arg("--global")
+            .arg("user.email")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()?
+            .success();
+        if !git_email_already_set || git.overwrite_existing.unwrap_or_default() {
                 println!("==============================================================================");
-                let _status = std::process::Command::new("sudo")
-                    .arg("apt")
-                    .arg("install")
-                    .arg("--yes")
-                    .arg("firefox")
-                    .arg("thunderbird")
+                let _status = std::process::Command::new("git")
+                    .arg("config")
+                    .arg("--global")
+                    .arg("user.email")
+                    .arg(git_email)
                     .status()?;
                 println!("==============================================================================");
+            }
+    }
 
-                create_file_with_content_if_not_exists_root(FIREFOX_PPA_FILENAME, FIREFOX_PPA_FILE_CONTENT)?;
-                create_file_with_content_if_not_exists_root(THUNDERBIRD_PPA_FILENAME, THUNDERBIRD_PPA_FILE_CONTENT)?;
-            } else {
-                println!("Firefox is already installed");
+    if let Some(config) = git.config.as_ref() {
+        for (key, values) in flatten_git_config("", config) {
+            git_config_set(&["--global"], &key, &values)?;
+        }
+    }
+
+    for include_if in git.include_if.iter().flatten() {
+        setup_git_include_if(include_if)?;
+    }
+
+    Ok(())
+}
+
+/// Flattens nested tables like `[git.config.alias]` into git config keys like `alias.co`. Arrays become multi-valued keys.
+fn flatten_git_config(prefix: &str, table: &toml::Table) -> Vec<(String, Vec<String>)> {
+    let mut entries = Vec::new();
+    for (key, value) in table {
+        let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::Table(table) => entries.append(&mut flatten_git_config(&key, table)),
+            toml::Value::Array(array) => entries.push((key, array.iter().map(git_config_value).collect())),
+            value => entries.push((key, vec![git_config_value(value)])),
+        }
+    }
+    entries
+}
+
+fn git_config_value(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Sets a (possibly multi-valued) git config key, unless it already has exactly these values.
+fn git_config_set(scope: &[&str], key: &str, values: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("git")
+        .arg("config")
+        .args(scope)
+        .arg("--get-all")
+        .arg(key)
+        .output()?;
+    let current = String::from_utf8(output.stdout)?;
+    if current.lines().eq(values.iter().map(String::as_str)) {
+        return Ok(());
+    }
+
+    println!("Setting git config {} to {}", key, values.join(", "));
+    if !current.is_empty() {
+        let _status = std::process::Command::new("git")
+            .arg("config")
+            .args(scope)
+            .arg("--unset-all")
+            .arg(key)
+            .status()?;
+    }
+    for value in values {
+        let _status = std::process::Command::new("git")
+            .arg("config")
+            .args(scope)
+            .arg("--add")
+            .arg(key)
+            .arg(value)
+            .status()?;
+    }
+    Ok(())
+}
+
+fn setup_git_include_if(include_if: &GitIncludeIf) -> Result<(), Box<dyn std::error::Error>> {
+    let name = include_if.condition.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let name = name.split('-').filter(|part| !part.is_empty()).collect::<Vec<&str>>().join("-");
+    // Different conditions can map to the same readable name (like gitdir:~/work/ and gitdir:~/work)
+    let include_file = format!("{}/git/localsetup/{}-{}.gitconfig", get_config_path(), &hash_string(&include_if.condition)[..8], name);
+    print!("Setting up git config for {} … ", include_if.condition);
+
+    let mut entries = Vec::new();
+    if let Some(name) = include_if.name.as_ref() {
+        entries.push(("user.name".to_string(), vec![name.clone()]));
+    }
+    if let Some(email) = include_if.email.as_ref() {
+        entries.push(("user.email".to_string(), vec![email.clone()]));
+    }
+    entries.append(&mut flatten_git_config("", &include_if.config.clone().unwrap_or_default()));
+
+    // Let git render the file, so quoting and escaping are right, and only replace the actual file if it changed
+    let rendered_file = include_file.clone() + ".new";
+    if let Some((directory, _)) = include_file.rsplit_once("/") {
+        std::fs::create_dir_all(directory)?;
+    }
+    std::fs::write(&rendered_file, MANAGED_FILE_HEADER)?;
+    for (key, values) in entries {
+        for value in values {
+            let _status = std::process::Command::new("git")
+                .arg("config")
+                .arg("--file")
+                .arg(&rendered_file)
+                .arg("--add")
+                .arg(&key)
+                .arg(&value)
+                .status()?;
+        }
+    }
+    let content = std::fs::read_to_string(&rendered_file)?;
+    std::fs::remove_file(&rendered_file)?;
+    let changed = write_file_if_changed(&include_file, &content)?;
+
+    let include_key = format!("includeIf.{}.path", include_if.condition);
+    let output = std::process::Command::new("git")
+        .arg("config")
+        .arg("--global")
+        .arg("--get-all")
+        .arg(&include_key)
+        .output()?;
+    if String::from_utf8(output.stdout)?.lines().any(|path| path == include_file) {
+        println!("{}", if changed { "done" } else { "Already set up" });
+        return Ok(());
+    }
+
+    let _status = std::process::Command::new("git")
+        .arg("config")
+        .arg("--global")
+        .arg("--add")
+        .arg(&include_key)
+        .arg(&include_file)
+        .status()?;
+    println!("done");
+    Ok(())
+}
+
+fn setup_git_signing(signing: &GitSigning, git_email: Option<&String>, default_ssh_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Setting up commit signing with {} …", signing.method);
+
+    let Some(email) = signing.email.as_ref().or(git_email) else {
+        println!("Error! An email is needed for commit signing, set git.email or git.signing.email");
+        return Ok(());
+    };
+
+    let signing_key = match signing.method.as_str() {
+        "ssh" => {
+            let public_key_path = signing.key.as_ref()
+                .map(|path| expand_home(path))
+                .unwrap_or(default_ssh_key.to_string() + ".pub");
+            let Ok(public_key) = std::fs::read_to_string(&public_key_path) else {
+                println!("Error! Public key {} does not exist", public_key_path);
+                return Ok(());
+            };
+            let public_key = public_key.split_whitespace().take(2).collect::<Vec<&str>>().join(" ");
+
+            let allowed_signers = signing.allowed_signers.as_ref()
+                .map(|path| expand_home(path))
+                .unwrap_or(get_config_path() + "/git/allowed_signers");
+            ensure_line_in_file(&allowed_signers, &format!("{} namespaces=\"git\" {}", email, public_key))?;
+            git_config_set(&["--global"], "gpg.ssh.allowedSignersFile", &[allowed_signers])?;
+
+            public_key_path
+        }
+        "gpg" => {
+            let key = match signing.key.as_ref() {
+                Some(key) => Some(key.clone()),
+                None => {
+                    // The fingerprint is in the first "fpr" record after the "sec" record of the secret key
+                    let output = std::process::Command::new("gpg")
+                        .arg("--list-secret-keys")
+                        .arg("--with-colons")
+                        .arg(email)
+                        .output()?;
+                    String::from_utf8(output.stdout)?
+                        .lines()
+                        .skip_while(|line| !line.starts_with("sec:"))
+                        .find(|line| line.starts_with("fpr:"))
+                        .and_then(|line| line.split(':').nth(9))
+                        .map(str::to_owned)
+                }
+            };
+            let Some(key) = key else {
+                println!("Error! No GPG secret key found for {}", email);
+                return Ok(());
+            };
+            key
+        }
+        method => {
+            println!("Error! Unknown signing method '{}', use 'ssh' or 'gpg'", method);
+            return Ok(());
+        }
+    };
+
+    let format = if signing.method == "ssh" { "ssh" } else { "openpgp" };
+    git_config_set(&["--global"], "gpg.format", &[format.to_string()])?;
+    git_config_set(&["--global"], "user.signingkey", &[signing_key])?;
+    git_config_set(&["--global"], "commit.gpgsign", &[signing.sign_commits.unwrap_or(true).to_string()])?;
+    git_config_set(&["--global"], "tag.gpgsign", &[signing.sign_tags.unwrap_or(true).to_string()])?;
+
+    Ok(())
+}
+
+/// Where the output of setting up a repository goes: directly to the terminal, or (when setting up repositories in
+/// parallel) to a log file per repository. With `stderr`, terminal output goes to stderr instead of stdout, which keeps
+/// the output of `localsetup status --json` clean while getting the Setupfile.
+struct RepositoryLog {
+    file: Option<std::fs::File>,
+    stderr: bool,
+}
+
+impl RepositoryLog {
+    fn print(&self, message: &str) {
+        match self.file.as_ref() {
+            Some(mut file) => {
+                let _ = write!(file, "{}", message);
+            }
+            None if self.stderr => eprint!("{}", message),
+            None => {
+                print!("{}", message);
+                let _ = std::io::stdout().flush();
             }
+        }
+    }
 
-            print!("Ensuring Firefox will be installed from PPA … ");
-            if path_exists(FIREFOX_PPA_FILENAME) && path_exists(THUNDERBIRD_PPA_FILENAME) {
-                println!("already ensured");
-            } else {
-                create_file_with_content_if_not_exists_root(FIREFOX_PPA_FILENAME, FIREFOX_PPA_FILE_CONTENT)?;
-                create_file_with_content_if_not_exists_root(THUNDERBIRD_PPA_FILENAME, THUNDERBIRD_PPA_FILE_CONTENT)?;
-                println!("done");
+    fn println(&self, message: &str) {
+        self.print(&format!("{}\n", message));
+    }
+
+    fn run(&self, command: &mut std::process::Command) -> Result<std::process::ExitStatus, Box<dyn std::error::Error>> {
+        match self.file.as_ref() {
+            Some(file) => {
+                // Nobody could answer a prompt while running in parallel. ssh asks on the terminal instead of stdin, so it
+                // needs batch mode as well (unless the user chose an ssh command already).
+                command.stdin(std::process::Stdio::null())
+                    .stdout(file.try_clone()?)
+                    .stderr(file.try_clone()?)
+                    .env("GIT_TERMINAL_PROMPT", "0");
+                if std::env::var_os("GIT_SSH_COMMAND").is_none() {
+                    command.env("GIT_SSH_COMMAND", "ssh -o BatchMode=yes");
+                }
+                Ok(command.status()?)
+            }
+            None => {
+                if self.stderr {
+                    command.stdout(std::io::stderr());
+                }
+                self.println("==============================================================================");
+                let status = command.status()?;
+                self.println("==============================================================================");
+                Ok(status)
             }
         }
-        PpaOrMozilla::Mozilla => {
-            // Install according to https://support.mozilla.org/de/kb/firefox-unter-linux-installieren#w_installation-uber-die-paketverwaltung-ihrer-distribution
-            print!("Installing Mozilla repository … ");
-            if !package_installed("firefox")? {
-                println!("");
-                println!("==============================================================================");
-                let _status = std::process::Command::new("sudo")
-                    .arg("install")
-                    .arg("-d")
-                    .arg("-m")
-                    .arg("0755")
-                    .arg("/etc/apt/keyrings")
-                    .status()?;
-                println!("==============================================================================");
+    }
+}
+
+fn run_git(directory: &str, args: &[&str], log: &RepositoryLog) -> Result<bool, Box<dyn std::error::Error>> {
+    let status = log.run(std::process::Command::new("git")
+        .args(args)
+        .current_dir(directory))?;
+    Ok(status.success())
+}
+
+fn git_output(directory: &str, args: &[&str]) -> Result<String, Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("git")
+        .args(args)
+        .current_dir(directory)
+        .stderr(std::process::Stdio::null())
+        .output()?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Lists the repositories of an organisation (or group) or user on a forge, as (name, clone URL) pairs, already filtered
+/// by the set's options.
+fn list_repository_set(set: &RepositorySet) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let api_url = forge_api_url(set.forge, set.base_url.as_ref())?;
+    let token = read_secret(set.token_env.as_ref(), set.token_file.as_ref())?;
+    let path = match (set.forge, set.org.as_ref(), set.user.as_ref()) {
+        (Forge::Github, Some(org), None) => format!("/orgs/{}/repos", org),
+        (Forge::Gitlab, Some(org), None) => format!("/groups/{}/projects", org.replace('/', "%2F")),
+        (Forge::Gitea, Some(org), None) => format!("/orgs/{}/repos", org),
+        (Forge::Gitlab, None, Some(user)) => format!("/users/{}/projects", user),
+        (_, None, Some(user)) => format!("/users/{}/repos", user),
+        _ => return Err("Exactly one of org and user is needed".into()),
+    };
+    let (name_field, ssh_field, https_field) = match set.forge {
+        Forge::Gitlab => ("path", "ssh_url_to_repo", "http_url_to_repo"),
+        Forge::Github | Forge::Gitea => ("name", "ssh_url", "clone_url"),
+    };
+    let per_page = 50;
+    let ssh = match set.protocol.as_deref().unwrap_or("ssh") {
+        "ssh" => true,
+        "https" => false,
+        protocol => return Err(format!("Unknown protocol '{}', use 'ssh' or 'https'", protocol).into()),
+    };
+
+    let client = reqwest::blocking::Client::new();
+    let mut repositories = Vec::new();
+    for page in 1.. {
+        // Gitea and Forgejo call the page size limit
+        let url = format!("{}{}?per_page={2}&limit={2}&page={3}", api_url, path, per_page, page);
+        let listing = forge_request(&client, reqwest::Method::GET, &url, set.forge, token.as_ref())
+            .send()?
+            .error_for_status()?
+            .json::<Vec<serde_json::Value>>()?;
+
+        for repository in &listing {
+            let name = repository[name_field].as_str().unwrap_or_default();
+            let archived = repository["archived"].as_bool().unwrap_or_default();
+            let fork = repository["fork"].as_bool().unwrap_or_default() || !repository["forked_from_project"].is_null();
+            if name.is_empty()
+                || (archived && !set.archived.unwrap_or_default())
+                || (fork && !set.forks.unwrap_or_default())
+                || !set.include.as_ref().is_none_or(|include| include.iter().any(|pattern| glob_match(pattern, name)))
+                || set.exclude.iter().flatten().any(|pattern| glob_match(pattern, name)) {
+                continue;
+            }
+            let Some(url) = repository[if ssh { ssh_field } else { https_field }].as_str().filter(|url| !url.is_empty()) else {
+                continue;
+            };
+            repositories.push((name.to_string(), url.to_string()));
+        }
+
+        if listing.len() < per_page {
+            break;
+        }
+    }
+    Ok(repositories)
+}
+
+/// All repositories to set up: the ones listed in `repositories`, followed by those from `repository_sets`. Messages go
+/// to stderr, so they do not end up in the output of `localsetup status --json`.
+fn expand_repositories(setup: &Setupfile) -> Vec<Repository> {
+    let mut repositories = setup.repositories.clone().unwrap_or_default();
+
+    for set in setup.repository_sets.iter().flatten() {
+        let owner = set.org.as_ref().or(set.user.as_ref()).cloned().unwrap_or_default();
+        eprint!("Listing repositories of {} on {:?} … ", owner, set.forge);
+        let listing = match list_repository_set(set) {
+            Ok(listing) => listing,
+            Err(err) => {
+                eprintln!("ERROR\n{}", err);
+                continue;
+            }
+        };
+        eprintln!("{} found", listing.len());
+
+        for (name, source) in listing {
+            let target = set.target.replace("{org}", &owner).replace("{name}", &name);
+            // Repositories listed explicitly take precedence
+            if repositories.iter().any(|repository| expand_home(&repository.target) == expand_home(&target)) {
+                continue;
+            }
+            repositories.push(Repository {
+                source,
+                target,
+                update: set.update,
+                synchronise: set.synchronise,
+                ..Default::default()
+            });
+        }
+    }
+
+    repositories
+}
+
+fn repository_remote(repository: &Repository) -> &str {
+    repository.origin_name.as_deref().unwrap_or("origin")
+}
+
+/// The URL of a remote as configured, i.e. without `insteadOf` rewrites, so it can be compared with the source.
+fn remote_url(target: &str, remote: &str) -> Result<String, Box<dyn std::error::Error>> {
+    git_output(target, &["config", "--get", &format!("remote.{}.url", remote)])
+}
+
+fn same_url(a: &str, b: &str) -> bool {
+    let normalise = |url: &str| url.trim_end_matches('/').trim_end_matches(".git").to_owned();
+    normalise(a) == normalise(b)
+}
+
+/// The HTTPS equivalent of an SSH URL like `git@host:org/repo.git` or `ssh://git@host:port/org/repo.git`.
+fn ssh_to_https_url(url: &str) -> Option<String> {
+    let (host, path) = if let Some(rest) = url.strip_prefix("ssh://") {
+        let (host, path) = rest.split_once('/')?;
+        (host.split(':').next()?, path)
+    } else if !url.contains("://") {
+        url.split_once(':')?
+    } else {
+        return None;
+    };
+    let host = host.rsplit('@').next()?;
+    Some(format!("https://{}/{}", host, path.trim_start_matches('/')))
+}
+
+/// Whether connecting to an SSH URL fails because the key is not accepted (e.g. not registered with the forge yet).
+fn ssh_auth_fails(url: &str) -> bool {
+    let mut command = std::process::Command::new("git");
+    command.args(["ls-remote", "--quiet", url, "HEAD"])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .env("GIT_TERMINAL_PROMPT", "0");
+    // This only probes the key, so ssh must not ask for a passphrase on the terminal either (unless the user chose an
+    // ssh command already)
+    if std::env::var_os("GIT_SSH_COMMAND").is_none() {
+        command.env("GIT_SSH_COMMAND", "ssh -o BatchMode=yes");
+    }
+    let Ok(output) = command.output() else {
+        return false;
+    };
+    !output.status.success() && String::from_utf8_lossy(&output.stderr).contains("Permission denied")
+}
+
+/// Decides what to do after cloning `url` failed, depending on `on_ssh_auth_failure`: nothing (`fail`), cloning via
+/// HTTPS instead (`https-fallback`) or showing the public keys until they are added to the forge (`retry`). Returns the
+/// URL to clone from again, if any.
+fn ssh_auth_failure_retry_url(url: &str, policy: Option<&str>, log: &RepositoryLog) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let policy = policy.unwrap_or("fail");
+    if !["fail", "https-fallback", "retry"].contains(&policy) {
+        return Err(format!("Unknown on_ssh_auth_failure '{}', use 'fail', 'https-fallback' or 'retry'", policy).into());
+    }
+    let Some(https_url) = ssh_to_https_url(url) else {
+        return Ok(None);
+    };
+    if policy == "fail" || !ssh_auth_fails(url) {
+        return Ok(None);
+    }
+
+    if policy == "https-fallback" {
+        log.println(&format!("SSH key not accepted for {}, cloning via {} instead", url, https_url));
+        return Ok(Some(https_url));
+    }
+
+    // Nobody could answer a prompt while running in parallel
+    if log.file.is_some() || !is_interactive() {
+        return Ok(None);
+    }
+    loop {
+        log.println(&format!("The SSH key is not accepted for {}. Add one of these public keys to your account:", url));
+        for entry in std::fs::read_dir(get_home() + "/.ssh")? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "pub") {
+                log.print(&std::fs::read_to_string(path)?);
+            }
+        }
+        log.print("Press Enter to try again, or type 'skip' to give up: ");
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if answer.trim() == "skip" {
+            return Ok(None);
+        }
+        if !ssh_auth_fails(url) {
+            return Ok(Some(url.to_string()));
+        }
+    }
+}
+
+/// Switches a remote that was cloned via the HTTPS fallback back to SSH, as soon as the SSH key is accepted.
+fn restore_ssh_remote(source: &str, policy: Option<&str>, target: &str, remote: &str, log: &RepositoryLog) -> Result<(), Box<dyn std::error::Error>> {
+    if policy != Some("https-fallback") {
+        return Ok(());
+    }
+    if let Some(https_url) = ssh_to_https_url(source)
+        && same_url(&remote_url(target, remote)?, &https_url)
+        && !ssh_auth_fails(source) {
+        log.println(&format!("SSH key accepted for {}, switching remote {} back to it", source, remote));
+        if !run_git(target, &["remote", "set-url", remote, source], log)? {
+            return Err(format!("Could not change remote {}", remote).into());
+        }
+    }
+    Ok(())
+}
+
+/// The remote of an existing checkout that points to the repository's source (or its HTTPS equivalent after the HTTPS
+/// fallback), preferring the configured remote name.
+fn find_source_remote(repository: &Repository, target: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let configured = repository_remote(repository);
+    let https_url = ssh_to_https_url(&repository.source).filter(|_| repository.on_ssh_auth_failure.as_deref() == Some("https-fallback"));
+    let mut remotes = git_output(target, &["remote"])?.lines().map(str::to_owned).collect::<Vec<String>>();
+    remotes.sort_by_key(|remote| remote != configured);
+    for remote in remotes {
+        let url = remote_url(target, &remote)?;
+        if same_url(&url, &repository.source) || https_url.as_ref().is_some_and(|https_url| same_url(&url, https_url)) {
+            return Ok(Some(remote));
+        }
+    }
+    Ok(None)
+}
+
+fn source_remote(repository: &Repository, target: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(find_source_remote(repository, target)?.unwrap_or(repository_remote(repository).to_owned()))
+}
+
+fn move_aside(target: &str, log: &RepositoryLog) -> Result<(), Box<dyn std::error::Error>> {
+    let moved = format!("{}.localsetup-{}", target.trim_end_matches('/'), get_date("%Y%m%d%H%M%S")?);
+    log.println(&format!("Moving {} aside to {}", target, moved));
+    std::fs::rename(target, moved)?;
+    Ok(())
+}
+
+/// Checks what is at an existing target. Returns whether the repository still needs to be cloned (into an empty
+/// directory, or after moving the old one aside), or fails if the target is something else and `existing` does not
+/// allow handling it.
+fn inspect_existing_target(repository: &Repository, target: &str, log: &RepositoryLog) -> Result<bool, Box<dyn std::error::Error>> {
+    let policy = repository.existing.as_deref().unwrap_or("error");
+    if !["error", "set-url", "add-remote", "move-aside"].contains(&policy) {
+        return Err(format!("Unknown existing policy '{}', use 'error', 'set-url', 'add-remote' or 'move-aside'", policy).into());
+    }
+
+    let path = std::path::Path::new(target);
+    if path.is_dir() && std::fs::read_dir(path)?.next().is_none() {
+        return Ok(true);
+    }
+
+    if !path_exists(&format!("{}/.git", target.trim_end_matches('/'))) {
+        if policy == "move-aside" {
+            move_aside(target, log)?;
+            return Ok(true);
+        }
+        return Err(format!("{} exists, but is not a git repository", repository.target).into());
+    }
+
+    if find_source_remote(repository, target)?.is_some() {
+        return Ok(false);
+    }
+
+    let remote = repository_remote(repository);
+    let url = remote_url(target, remote)?;
+    match policy {
+        "set-url" if url.is_empty() => {
+            if !run_git(target, &["remote", "add", remote, &repository.source], log)? {
+                return Err(format!("Could not add remote {}", remote).into());
+            }
+        }
+        "set-url" => {
+            log.println(&format!("Changing remote {} from {} to {}", remote, url, repository.source));
+            if !run_git(target, &["remote", "set-url", remote, &repository.source], log)? {
+                return Err(format!("Could not change remote {}", remote).into());
+            }
+        }
+        "add-remote" => {
+            if !run_git(target, &["remote", "add", "localsetup", &repository.source], log)?
+                || !run_git(target, &["fetch", "localsetup"], log)? {
+                return Err("Could not add remote localsetup".into());
+            }
+        }
+        "move-aside" => {
+            move_aside(target, log)?;
+            return Ok(true);
+        }
+        _ => return Err(format!("{} is a clone of {} instead of {}", repository.target, url, repository.source).into()),
+    }
+    Ok(false)
+}
+
+fn checkout_ref(repository: &Repository, target: &str, git_ref: &str, log: &RepositoryLog) -> Result<(), Box<dyn std::error::Error>> {
+    let wanted = git_output(target, &["rev-parse", "--verify", "--quiet", &format!("{}^{{commit}}", git_ref)])?;
+    if !wanted.is_empty() && wanted == git_output(target, &["rev-parse", "HEAD"])? {
+        log.println(&format!("Repository {} is pinned at {}", repository.target, git_ref));
+        return Ok(());
+    }
+
+    if !wanted.is_empty() && run_git(target, &["-c", "advice.detachedHead=false", "checkout", "--detach", git_ref], log)? {
+        return Ok(());
+    }
+
+    // Refs that are not available locally (e.g. in shallow clones) need to be fetched first
+    let mut fetch = vec!["fetch".to_string()];
+    if let Some(depth) = repository.depth {
+        fetch.push(format!("--depth={}", depth));
+    }
+    fetch.push(source_remote(repository, target)?);
+    if git_ref.len() >= 7 && git_ref.chars().all(|c| c.is_ascii_hexdigit()) {
+        fetch.push(git_ref.to_string());
+    } else {
+        // Fetch tags as tags, so the pinned tag is known locally on the next run
+        fetch.push(format!("refs/tags/{0}:refs/tags/{0}", git_ref));
+    }
+    if !run_git(target, &fetch.iter().map(String::as_str).collect::<Vec<&str>>(), log)? {
+        return Err(format!("Could not fetch {}", git_ref).into());
+    }
+    if !run_git(target, &["-c", "advice.detachedHead=false", "checkout", "--detach", "FETCH_HEAD"], log)? {
+        return Err(format!("Could not check out {}", git_ref).into());
+    }
+    Ok(())
+}
+
+fn apply_repository_options(repository: &Repository, target: &str, log: &RepositoryLog) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(sparse) = repository.sparse.as_ref() {
+        let mut args = vec!["sparse-checkout", "set"];
+        args.extend(sparse.iter().map(String::as_str));
+        if !run_git(target, &args, log)? {
+            return Err("Could not set up sparse checkout".into());
+        }
+    }
+
+    if repository.recurse_submodules.unwrap_or_default() && !run_git(target, &["submodule", "update", "--init", "--recursive"], log)? {
+        return Err("Could not update submodules".into());
+    }
+
+    Ok(())
+}
+
+fn setup_repository(repository: &Repository, log: &RepositoryLog) -> Result<bool, Box<dyn std::error::Error>> {
+    let target = expand_home(&repository.target);
+
+    if path_exists(&target) && !inspect_existing_target(repository, &target, log)? {
+        log.println(&format!("Existing repository: {} -> {}", repository.source, repository.target));
+        if let Some(remote) = find_source_remote(repository, &target)? {
+            restore_ssh_remote(&repository.source, repository.on_ssh_auth_failure.as_deref(), &target, &remote, log)?;
+        }
+        return Ok(false)
+    }
+
+    log.println(&format!("Creating repository: {} -> {}", repository.source, repository.target));
+    let (mut base, dir) = target.rsplit_once("/").expect("Invalid directory");
+    if dir == "" {
+        (base, _)  = base.rsplit_once("/").expect("Invalid directory");
+    }
+
+    if !path_exists(base) {
+        std::fs::create_dir_all(base)?;
+    }
+
+    let mut clone = vec!["clone".to_string()];
+    if let Some(branch) = repository.branch.as_ref() {
+        clone.push(format!("--branch={}", branch));
+    }
+    if let Some(depth) = repository.depth {
+        clone.push(format!("--depth={}", depth));
+    }
+    if repository.recurse_submodules.unwrap_or_default() {
+        clone.push("--recurse-submodules".to_string());
+    }
+    if repository.sparse.is_some() {
+        clone.push("--sparse".to_string());
+    }
+    clone.push(format!("--origin={}", repository_remote(repository)));
+    clone.push("--".to_string());
+    clone.push(repository.source.clone());
+    clone.push(target.clone());
+
+    if !run_git(base, &clone.iter().map(String::as_str).collect::<Vec<&str>>(), log)? {
+        let retry_url = ssh_auth_failure_retry_url(&repository.source, repository.on_ssh_auth_failure.as_deref(), log)?;
+        let Some(retry_url) = retry_url else {
+            return Err(format!("Could not clone {}", repository.source).into());
+        };
+        let source_index = clone.len() - 2;
+        clone[source_index] = retry_url;
+        if !run_git(base, &clone.iter().map(String::as_str).collect::<Vec<&str>>(), log)? {
+            return Err(format!("Could not clone {}", repository.source).into());
+        }
+    }
+
+    if let Some(git_ref) = repository.git_ref.as_ref() {
+        checkout_ref(repository, &target, git_ref, log)?;
+    }
+    apply_repository_options(repository, &target, log)?;
+
+    Ok(true)
+}
+
+fn update_repository(repository: &Repository, log: &RepositoryLog) -> Result<(), Box<dyn std::error::Error>> {
+    let target = expand_home(&repository.target);
+
+    if !path_exists(&target) {
+        return Err(format!("Repository {} does not exist", repository.target).into());
+    }
+
+    log.println(&format!("Updating repository: {} -> {}", repository.source, repository.target));
+
+    if let Some(git_ref) = repository.git_ref.as_ref() {
+        // A pinned ref stays pinned, there is nothing to pull
+        checkout_ref(repository, &target, git_ref, log)?;
+    } else if let Some(branch) = repository.branch.as_ref() {
+        if git_output(&target, &["rev-parse", "--abbrev-ref", "HEAD"])? != *branch && !run_git(&target, &["checkout", branch], log)? {
+            return Err(format!("Could not check out branch {}", branch).into());
+        }
+        if !run_git(&target, &["pull", &source_remote(repository, &target)?, branch], log)? {
+            return Err("Could not pull".into());
+        }
+    } else {
+        let remote = source_remote(repository, &target)?;
+        let mut pull = vec!["pull".to_string()];
+        // The upstream of the branch may be a different remote, e.g. after `existing = "add-remote"`
+        if remote != repository_remote(repository) {
+            pull.push(remote);
+            pull.push(git_output(&target, &["symbolic-ref", "--quiet", "--short", "HEAD"])?);
+        }
+        if !run_git(&target, &pull.iter().map(String::as_str).collect::<Vec<&str>>(), log)? {
+            return Err("Could not pull".into());
+        }
+    }
+
+    apply_repository_options(repository, &target, log)?;
+
+    Ok(())
+}
+
+fn git_operation_in_progress(target: &str) -> Result<Option<&'static str>, Box<dyn std::error::Error>> {
+    let git_dir = git_output(target, &["rev-parse", "--absolute-git-dir"])?;
+    let operations = [("rebase-merge", "rebase"), ("rebase-apply", "rebase"), ("MERGE_HEAD", "merge"), ("CHERRY_PICK_HEAD", "cherry-pick")];
+    Ok(operations.into_iter()
+        .find(|(file, _)| path_exists(&format!("{}/{}", git_dir, file)))
+        .map(|(_, operation)| operation))
+}
+
+/// Commits local changes, rebases them onto the remote branch and pushes them. Stops (without pushing) as soon as
+/// anything needs manual attention, so a repository is never left in the middle of a rebase.
+fn synchronise_repository(repository: &Repository, log: &RepositoryLog) -> Result<(), Box<dyn std::error::Error>> {
+    let target = expand_home(&repository.target);
+
+    if !path_exists(&target) {
+        return Err(format!("Repository {} does not exist", repository.target).into());
+    }
+
+    log.println(&format!("Synchronising repository: {} -> {}", repository.source, repository.target));
+
+    if let Some(operation) = git_operation_in_progress(&target)? {
+        return Err(format!("A {} is in progress, finish or abort it first", operation).into());
+    }
+
+    let current_branch = git_output(&target, &["symbolic-ref", "--quiet", "--short", "HEAD"])?;
+    if current_branch.is_empty() {
+        return Err("HEAD is detached, not synchronising".into());
+    }
+    let branch = repository.branch.clone().unwrap_or(current_branch.clone());
+    if branch != current_branch {
+        return Err(format!("Branch {} is checked out instead of {}", current_branch, branch).into());
+    }
+    let remote = &source_remote(repository, &target)?;
+
+    let status = git_output(&target, &["status", "--porcelain"])?;
+    let has_untracked = status.lines().any(|line| line.starts_with("??"));
+    let add = match repository.untracked.as_deref().unwrap_or("ignore") {
+        "ignore" => "--update",
+        "commit" => "--all",
+        "abort" if has_untracked => return Err("There are untracked files".into()),
+        "abort" => "--update",
+        policy => return Err(format!("Unknown untracked policy '{}', use 'ignore', 'commit' or 'abort'", policy).into()),
+    };
+    if !run_git(&target, &["add", add], log)? {
+        return Err("Could not add changes".into());
+    }
+
+    let has_staged_changes = !std::process::Command::new("git")
+        .args(["diff", "--cached", "--quiet"])
+        .current_dir(&target)
+        .status()?
+        .success();
+    if has_staged_changes {
+        let message = repository.commit_message.as_deref()
+            .unwrap_or("autocommit on {hostname} at {date}")
+            .replace("{hostname}", &get_hostname()?)
+            .replace("{date}", &get_date("%F %R")?);
+        if !run_git(&target, &["commit", "--message", &message], log)? {
+            return Err("Could not commit changes".into());
+        }
+    }
+
+    if !run_git(&target, &["pull", "--rebase", remote, &branch], log)? {
+        if git_operation_in_progress(&target)?.is_some() {
+            run_git(&target, &["rebase", "--abort"], log)?;
+            return Err("Conflicts while rebasing onto the remote branch, rebase aborted and nothing pushed".into());
+        }
+        return Err("Could not pull, nothing pushed".into());
+    }
+
+    let ahead = git_output(&target, &["rev-list", "--count", &format!("{}/{}..HEAD", remote, branch)])?;
+    if ahead != "0" && !run_git(&target, &["push", remote, &format!("HEAD:{}", branch)], log)? {
+        return Err("Could not push".into());
+    }
+
+    Ok(())
+}
+
+fn run_repository_command(command: &str, target: &str, log: &RepositoryLog) -> Result<(), Box<dyn std::error::Error>> {
+    log.println(&format!("Running: {}", command));
+    let status = log.run(std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(target))?;
+    if !status.success() {
+        return Err(format!("Command '{}' failed", command).into());
+    }
+    Ok(())
+}
+
+/// Clones, updates or synchronises a single repository and runs its commands. Returns what was done.
+fn process_repository(repository: &Repository, log: &RepositoryLog) -> Result<&'static str, Box<dyn std::error::Error>> {
+    let target = expand_home(&repository.target);
+    let action = if setup_repository(repository, log)? {
+        if let Some(run_once) = repository.run_once.as_ref() {
+            run_repository_command(run_once, &target, log)?;
+        }
+        "cloned"
+    } else if repository.synchronise.unwrap_or_default() {
+        synchronise_repository(repository, log)?;
+        "synchronised"
+    } else if repository.update.unwrap_or_default() {
+        update_repository(repository, log)?;
+        "updated"
+    } else {
+        "exists"
+    };
+
+    if let Some(run_everytime) = repository.run_everytime.as_ref() {
+        run_repository_command(run_everytime, &target, log)?;
+    }
+
+    Ok(action)
+}
+
+#[derive(Default)]
+struct RepositoryQueue {
+    pending: Vec<usize>,
+    running: usize,
+    finished: usize,
+    succeeded: Vec<String>,
+    failed: Vec<String>,
+    failures: Vec<(String, String)>,
+}
+
+/// Sets up all repositories with up to `jobs` at the same time, but only after the repositories they depend on. With more
+/// than one job, the output of each repository goes to a log file, which is kept if something fails. Returns the
+/// repositories that failed, with the reason.
+fn setup_repositories(repositories: &[Repository], jobs: usize) -> Vec<(String, String)> {
+    let parallel = jobs > 1;
+    let targets = repositories.iter().map(|repository| expand_home(&repository.target)).collect::<Vec<String>>();
+    let log_dir = get_cache_path() + "/logs";
+    if parallel {
+        println!("Setting up {} repositories with {} parallel jobs …", repositories.len(), jobs);
+        let _ = std::fs::create_dir_all(&log_dir);
+    }
+
+    let queue = std::sync::Mutex::new(RepositoryQueue{pending: (0..repositories.len()).collect(), ..Default::default()});
+    let queue_changed = std::sync::Condvar::new();
+
+    let worker = || loop {
+        let mut state = queue.lock().unwrap();
+        let index = loop {
+            if state.pending.is_empty() {
+                return;
+            }
+
+            let mut blocked_by_failure = None;
+            let next = state.pending.iter().position(|&index| {
+                repositories[index].depends_on.iter().flatten().all(|dependency| {
+                    let dependency = expand_home(dependency);
+                    if state.failed.contains(&dependency) || !targets.contains(&dependency) {
+                        blocked_by_failure.get_or_insert((index, format!("Dependency {} could not be set up", dependency)));
+                    }
+                    state.succeeded.contains(&dependency)
+                })
+            });
+
+            if let Some(position) = next {
+                state.running += 1;
+                break state.pending.remove(position);
+            }
+
+            // Repositories whose dependencies failed (or do not exist, or depend on each other) can never be set up
+            let stuck = blocked_by_failure.or_else(|| (state.running == 0).then(|| (state.pending[0], "Dependencies form a cycle".to_string())));
+            if let Some((index, reason)) = stuck {
+                state.pending.retain(|&pending| pending != index);
+                state.finished += 1;
+                state.failed.push(targets[index].clone());
+                if parallel {
+                    println!("[{:>3}/{}] FAILED  {} ({})", state.finished, repositories.len(), repositories[index].target, reason);
+                } else {
+                    println!("Error! Repository {} not set up: {}", repositories[index].target, reason);
+                }
+                state.failures.push((repositories[index].target.clone(), reason));
+                queue_changed.notify_all();
+                continue;
+            }
+
+            state = queue_changed.wait(state).unwrap();
+        };
+        drop(state);
+
+        let repository = &repositories[index];
+        let log_file = format!("{}/{}.log", log_dir, hash_string(&targets[index])[..16].to_owned());
+        // Without its log file, a repository counts as failed instead of being set up with its output lost
+        let result = parallel.then(|| std::fs::File::create(&log_file)).transpose()
+            .map(|file| process_repository(repository, &RepositoryLog{file, stderr: false}));
+
+        let mut state = queue.lock().unwrap();
+        state.running -= 1;
+        state.finished += 1;
+        match result {
+            Ok(Ok(action)) => {
+                state.succeeded.push(targets[index].clone());
+                if parallel {
+                    let _ = std::fs::remove_file(&log_file);
+                    println!("[{:>3}/{}] {:<7} {}", state.finished, repositories.len(), action, repository.target);
+                }
+            }
+            Ok(Err(err)) => {
+                state.failed.push(targets[index].clone());
+                if parallel {
+                    println!("[{:>3}/{}] FAILED  {} ({}, log: {})", state.finished, repositories.len(), repository.target, err, log_file);
+                    state.failures.push((repository.target.clone(), format!("{} (log: {})", err, log_file)));
+                } else {
+                    println!("Error! {}", err);
+                    state.failures.push((repository.target.clone(), err.to_string()));
+                }
+            }
+            Err(err) => {
+                let reason = format!("Could not create log file {}: {}", log_file, err);
+                state.failed.push(targets[index].clone());
+                println!("[{:>3}/{}] FAILED  {} ({})", state.finished, repositories.len(), repository.target, reason);
+                state.failures.push((repository.target.clone(), reason));
+            }
+        }
+        queue_changed.notify_all();
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, repositories.len().max(1)) {
+            scope.spawn(worker);
+        }
+    });
+
+    queue.into_inner().unwrap().failures
+}
+
+fn install_packages(packages: Vec<String>, assume_yes: bool) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Installing packages … ");
+    if packages.len() > 0 {
+        println!("");
+        println!("==============================================================================");
+        let _status = if assume_yes {
+            std::process::Command::new("sudo")
+                .arg("apt")
+                .arg("install")
+                .arg("--yes")
+                .args(packages)
+                .status()
+                .expect("failed to execute process")
+        } else {
+            std::process::Command::new("sudo")
+                .arg("apt")
+                .arg("install")
+                .args(packages)
+                .status()
+                .expect("failed to execute process")
+        };
+
+        println!("==============================================================================");
+    } else {
+        println!("No packages to install");
+    }
+
+    Ok(())
+}
+
+fn remove_packages(packages: Vec<String>, assume_yes: bool) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Removing packages … ");
+    if packages.len() > 0 {
+        println!("");
+        println!("==============================================================================");
+        let _status = if assume_yes {
+            std::process::Command::new("sudo")
+                .arg("apt")
+                .arg("remove")
+                .arg("--yes")
+                .args(packages)
+                .status()?
+        } else {
+            std::process::Command::new("sudo")
+                .arg("apt")
+                .arg("remove")
+                .args(packages)
+                .status()?
+        };
+
+        println!("==============================================================================");
+    } else {
+        println!("No packages to remove");
+    }
+
+    Ok(())
+}
+
+
+fn set_xdg_user_dirs(xdg_user_dirs: &XdgUserDirs) -> Result<(), Box<dyn std::error::Error>> {
+    let move_existing = xdg_user_dirs.move_existing.unwrap_or(false);
+    let home = get_home();
+    let dirs = [("DESKTOP", &xdg_user_dirs.desktop),
+                ("DOWNLOAD", &xdg_user_dirs.download),
+                ("MUSIC", &xdg_user_dirs.music),
+                ("PICTURES", &xdg_user_dirs.pictures),
+                ("PUBLICSHARE", &xdg_user_dirs.publicshare),
+                ("TEMPLATES", &xdg_user_dirs.templates),
+                ("VIDEOS", &xdg_user_dirs.videos)];
+
+    for dir in dirs {
+        if let Some(new_location) = dir.1 {
+            let id = dir.0;
+
+            let output = std::process::Command::new("xdg-user-dir")
+                .arg(&id)
+                .output()?;
+            let current_location = &String::from_utf8(output.stdout).unwrap().trim().to_owned();
+
+            let new_location_absolute = new_location.replace("$HOME", &home);
+            // Current location might or might not be already absolute, but here we need to make sure
+            let current_location_absolute = current_location.replace("$HOME", &home);
+
+            #[cfg(debug_assertions)]
+            println!("'{}', '{}'", current_location_absolute, new_location_absolute);
+
+            if current_location_absolute == new_location_absolute {
+                println!("xdg-user-dir {} already at location {}", &id, &new_location);
+                continue;
+            }
+            println!("Setting xdg-user-dir {} to location {}", &id, &new_location);
+
+            let _status = std::process::Command::new("xdg-user-dirs-update")
+                .arg("--set")
+                .arg(&id)
+                .arg(&new_location_absolute)
+                .status()?;
+
+            if move_existing {
+                let current_location_absolute = current_location.replace("$HOME", &home);
+                print!("Moving xdg-user-dir {} from current location {} to new location {} … ", &id, &current_location_absolute, &new_location_absolute);
+
+                let (new_location_absolute_base, _) = new_location_absolute.trim_end_matches('/').rsplit_once("/").expect("Invalid directory");
+                let _status = std::process::Command::new("mkdir")
+                    .arg("-p")
+                    .arg(&new_location_absolute_base)
+                    .status()?;
+
+                let status = std::process::Command::new("mv")
+                    .arg(&current_location_absolute)
+                    .arg(&new_location_absolute)
+                    .status()?;
+
+                if status.success() {
+                    println!("Ok");
+                } else {
+                    println!("ERROR\nError moving directory. Does the new directory already exist?");
+                }
+            } else {
+                print!("Creating xdg-user-dir {} at to new location {} …", &id, &new_location_absolute);
+
+                let _status = std::process::Command::new("mkdir")
+                    .arg("-p")
+                    .arg(&new_location_absolute)
+                    .status()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn package_installed(package: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let status = std::process::Command::new("dpkg")
+        .arg("-s")
+        .arg(package)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?;
+
+    // Note, if a package is no longer installed, but the config files are still present, we will recognize this as
+    // installed (for dpkg its: "Status: deinstall ok config-files"). For now, we're okay with that!
+    //
+    // Also, if a package is marked as "hold" we will recognize this as installed. TODO: fix that!
+    return Ok(status.success())
+}
+
+const NOSNAPD_FILENAME: &str = "/etc/apt/preferences.d/nosnap.pref";
+const NOSNAPD_FILE_CONTENT: &str = "
+# To prevent repository packages from triggering the installation of snap,
+# this file forbids snapd from being installed by APT.
+
+Package: snapd
+Pin: release a=*
+Pin-Priority: -10
+";
+
+const FIREFOX_NOSNAP_FILENAME: &str = "/etc/apt/preferences.d/firefox-nosnap.pref";
+const FIREFOX_NOSNAP_FILE_CONTENT: &str = "
+Package: firefox*
+Pin: release o=Ubuntu*
+Pin-Priority: -1
+";
+
+const THUNDERBIRD_NOSNAP_FILENAME: &str = "/etc/apt/preferences.d/thunderbird-nosnap.pref";
+const THUNDERBIRD_NOSNAP_FILE_CONTENT: &str = "
+Package: thunderbird*
+Pin: release o=Ubuntu*
+Pin-Priority: -1
+";
+
+const FIREFOX_PPA_FILENAME: &str = "/etc/apt/preferences.d/firefox-ppa.pref";
+const FIREFOX_PPA_FILE_CONTENT: &str = "
+Package: firefox*
+Pin: release o=LP-PPA-mozillateam
+Pin-Priority: 501
+";
+
+const THUNDERBIRD_PPA_FILENAME: &str = "/etc/apt/preferences.d/thunderbird-ppa.pref";
+const THUNDERBIRD_PPA_FILE_CONTENT: &str = "
+Package: thunderbird*
+Pin: release o=LP-PPA-mozillateam
+Pin-Priority: 501
+";
+
+const FIREFOX_MOZILLA_REPOSITORY_FILENAME: &str = "/etc/apt/sources.list.d/mozilla.sources";
+const FIREFOX_MOZILLA_REPOSITORY_FILE_CONTENT: &str = "
+Types: deb
+URIs: https://packages.mozilla.org/apt
+Suites: mozilla
+Components: main
+Signed-By: /etc/apt/keyrings/packages.mozilla.org.asc
+";
+
+const FIREFOX_MOZILLA_FILENAME: &str = "/etc/apt/preferences.d/mozilla";
+const FIREFOX_MOZILLA_FILE_CONTENT: &str = "
+Package: *
+Pin: origin packages.mozilla.org
+Pin-Priority: 1000
+";
+
+fn create_file_with_content_if_not_exists_root(filename: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if path_exists(filename) {
+        return Ok(());
+    }
+
+    let (directory, basename) = filename.rsplit_once("/").unwrap();
+    let cache_path = get_cache_path();
+
+    std::fs::create_dir_all(cache_path.clone())?;
+    let mut file = std::fs::File::create(cache_path.clone() + "/" + basename)?;
+
+    use std::io::Write;
+    file.write_all(content.as_bytes())?;
+
+    let _status = std::process::Command::new("mkdir")
+                .arg("-p")
+                .arg(directory)
+                .status()?;
+
+    let _status = std::process::Command::new("sudo")
+                .arg("cp")
+                .arg(cache_path + "/" + basename)
+                .arg(filename)
+                .status()?;
+
+    Ok(())
+}
+
+enum PpaOrMozilla {
+    Ppa,
+    Mozilla,
+}
+
+fn ubuntu_remove_snap_and_install_firefox_ppa_or_mozilla(ppa_or_mozilla: PpaOrMozilla, assume_yes: bool) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Removing snap … ");
+    if package_installed("snapd")? {
+        println!("");
+
+        if !assume_yes && !is_interactive() {
+            println!("Skipping, removing snap needs to be confirmed interactively");
+            return Ok(())
+        }
+
+        if !assume_yes {
+            println!("Removing snap and switching from snap-installed firefox and thundebird to PPA\nfirefox and thundebird will remove all bookmarks, setting, emails, and\neverything else. Are you sure you want that? [y/n]");
+            loop {
+                let input_line = std::io::stdin().lines().next().unwrap()?;
+                if input_line == "n" {
+                    return Ok(())
+                }
+                if input_line == "y" {
+                    break;
+                }
+                println!("Please answer 'y' for yes or 'n' for no. Should all bookmarks, setting, emails,\nand everything else be deleted? [y/n]");
+            }
+        }
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("sudo")
+                .arg("apt")
+                .arg("purge")
+                .arg("--yes")
+                .arg("snapd")
+                .status()?;
+        println!("==============================================================================");
+    } else {
+        println!("snap already removed");
+    }
+
+    print!("Ensuring snap will never be installed again … ");
+    if path_exists(NOSNAPD_FILENAME) && path_exists(FIREFOX_NOSNAP_FILENAME) && path_exists(THUNDERBIRD_NOSNAP_FILENAME) {
+        println!("already safe");
+    } else {
+        create_file_with_content_if_not_exists_root(NOSNAPD_FILENAME, NOSNAPD_FILE_CONTENT)?;
+        create_file_with_content_if_not_exists_root(FIREFOX_NOSNAP_FILENAME, FIREFOX_NOSNAP_FILE_CONTENT)?;
+        create_file_with_content_if_not_exists_root(THUNDERBIRD_NOSNAP_FILENAME, THUNDERBIRD_NOSNAP_FILE_CONTENT)?;
+        println!("done");
+    }
+
+    match ppa_or_mozilla {
+        PpaOrMozilla::Ppa => {
+            print!("Installing Firefox from PPA … ");
+            if !package_installed("firefox")? {
+                println!("");
+                println!("==============================================================================");
+                let _status = std::process::Command::new("sudo")
+                    .arg("add-apt-repository")
+                    .arg("ppa:mozillateam/ppa")
+                    .status()?;
+                println!("==============================================================================");
+
+                println!("==============================================================================");
+                let _status = std::process::Command::new("sudo")
+                    .arg("apt")
+                    .arg("install")
+                    .arg("--yes")
+                    .arg("firefox")
+                    .arg("thunderbird")
+                    .status()?;
+                println!("==============================================================================");
+
+                create_file_with_content_if_not_exists_root(FIREFOX_PPA_FILENAME, FIREFOX_PPA_FILE_CONTENT)?;
+                create_file_with_content_if_not_exists_root(THUNDERBIRD_PPA_FILENAME, THUNDERBIRD_PPA_FILE_CONTENT)?;
+            } else {
+                println!("Firefox is already installed");
+            }
+
+            print!("Ensuring Firefox will be installed from PPA … ");
+            if path_exists(FIREFOX_PPA_FILENAME) && path_exists(THUNDERBIRD_PPA_FILENAME) {
+                println!("already ensured");
+            } else {
+                create_file_with_content_if_not_exists_root(FIREFOX_PPA_FILENAME, FIREFOX_PPA_FILE_CONTENT)?;
+                create_file_with_content_if_not_exists_root(THUNDERBIRD_PPA_FILENAME, THUNDERBIRD_PPA_FILE_CONTENT)?;
+                println!("done");
+            }
+        }
+        PpaOrMozilla::Mozilla => {
+            // Install according to https://support.mozilla.org/de/kb/firefox-unter-linux-installieren#w_installation-uber-die-paketverwaltung-ihrer-distribution
+            print!("Installing Mozilla repository … ");
+            if !package_installed("firefox")? {
+                println!("");
+                println!("==============================================================================");
+                let _status = std::process::Command::new("sudo")
+                    .arg("install")
+                    .arg("-d")
+                    .arg("-m")
+                    .arg("0755")
+                    .arg("/etc/apt/keyrings")
+                    .status()?;
+                println!("==============================================================================");
+
+                use std::io::Write;
+                println!("Downloading Mozilla signing key … ");
+                let body = reqwest::blocking::get("https://packages.mozilla.org/apt/repo-signing-key.gpg")?.text()?;
+                let cache_path = get_cache_path();
+                let mut file = std::fs::File::create(cache_path.clone() + "/mozilla_repo_signing_key.gpg")?;
+                file.write_all(body.as_bytes())?;
+
+                print!("Checking Mozilla signing key integrity … ");
+                let output = std::process::Command::new("gpg")
+                    .arg("gpg")
+                    .arg("-n")
+                    .arg("-q")
+                    .arg("--import")
+                    .arg("--import-options")
+                    .arg("import-show")
+                    .arg(cache_path.clone() + "/mozilla_repo_signing_key.gpg")
+                    .output()?;
+                if String::from_utf8_lossy(&output.stdout).contains("35BAA0B33E9EB396F59CA838C0BA5CE6DC6315A3") {
+                    println!("Ok");
+                } else {
+                    println!("ERROR: SIGNING KEY MISSMATCH. ABORTING.");
+                    panic!("ERROR: SIGNING KEY MISSMATCH. ABORTING.");
+                }
+
+                println!("Installing Mozilla repository key … ");
+
+                println!("==============================================================================");
+                let _status = std::process::Command::new("bash")
+                    .arg("-c")
+                    .arg("sudo mv ".to_string() + &cache_path + "/mozilla_repo_signing_key.gpg /etc/apt/keyrings/packages.mozilla.org.asc")
+                    .status()?;
+                println!("==============================================================================");
+
+                create_file_with_content_if_not_exists_root(FIREFOX_MOZILLA_REPOSITORY_FILENAME, FIREFOX_MOZILLA_REPOSITORY_FILE_CONTENT)?;
+
+                println!("==============================================================================");
+                let _status = std::process::Command::new("sudo")
+                    .arg("apt")
+                    .arg("update")
+                    .status()?;
+                println!("==============================================================================");
+
+                println!("==============================================================================");
+                let _status = std::process::Command::new("sudo")
+                    .arg("apt")
+                    .arg("install")
+                    .arg("--yes")
+                    .arg("firefox")
+                    .status()?;
+                println!("==============================================================================");
+            } else {
+                println!("Firefox is already installed");
+            }
+
+            print!("Ensuring Firefox will be installed from PPA … ");
+            if path_exists(FIREFOX_MOZILLA_FILENAME) {
+                println!("already ensured");
+            } else {
+                create_file_with_content_if_not_exists_root(FIREFOX_MOZILLA_FILENAME, FIREFOX_MOZILLA_FILE_CONTENT)?;
+                println!("done");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ubuntu_specifics(ubuntu: &Ubuntu) -> Result<(), Box<dyn std::error::Error>> {
+    if ubuntu.remove_snap_and_install_firefox_ppa.unwrap_or_default() {
+        ubuntu_remove_snap_and_install_firefox_ppa_or_mozilla(PpaOrMozilla::Ppa, ubuntu.remove_snap_and_install_firefox_ppa_yes_delete_my_bookmarks_and_everything.unwrap_or_default())?;
+    }
+
+    if ubuntu.remove_snap_and_install_firefox_mozilla.unwrap_or_default() {
+        ubuntu_remove_snap_and_install_firefox_ppa_or_mozilla(PpaOrMozilla::Mozilla, ubuntu.remove_snap_and_install_firefox_ppa_yes_delete_my_bookmarks_and_everything.unwrap_or_default())?;
+    }
+
+    Ok(())
+}
+
+fn setup_rustup(rustup: &Rustup) -> Result<(), Box<dyn std::error::Error>> {
+    let rustup_installed = std::process::Command::new("which")
+        .arg("rustup")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?
+        .success();
+
+    let rust_installed = std::process::Command::new("which")
+        .arg("rustup")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?
+        .success();
+
+    if !rust_installed && rustup.install_rust.unwrap_or_default() {
+        if !rustup_installed {
+            println!("Installing rustup … ");
+
+            let body = reqwest::blocking::get("https://sh.rustup.rs")?.text()?;
+
+            let cache_path = get_cache_path();
+
+            let mut file = std::fs::File::create(cache_path.clone() + "/rustup.sh")?;
+            use std::io::Write;
+            file.write_all(body.as_bytes())?;
+
+            println!("Installing rust … ");
+
+            println!("==============================================================================");
+            let _status = std::process::Command::new("sh")
+                .arg(cache_path + "/rustup.sh")
+                .arg("-y")
+                .status()?;
+            println!("==============================================================================");
+        } else {
+            println!("Rustup already installed");
+            println!("Installing rust …");
+
+            println!("==============================================================================");
+            let _status = std::process::Command::new("rustup")
+                .arg("toolchain")
+                .arg("install")
+                .arg("stable")
+                .status()?;
+            println!("==============================================================================");
+        }
+    }
+
+    if rustup_installed && rust_installed && rustup.update_rust.unwrap_or_default() {
+        println!("Rustup already installed");
+        println!("Rust already installed");
+        println!("Updating rust …");
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("rustup")
+            .arg("update")
+            .status()?;
+        println!("==============================================================================");
+    }
+
+    Ok(())
+}
+
+fn setup_uv(uv: &Uv) -> Result<(), Box<dyn std::error::Error>> {
+    let uv_installed = std::process::Command::new("which")
+    .arg("uv")
+    .stdout(std::process::Stdio::null())
+    .stderr(std::process::Stdio::null())
+    .status()?
+    .success();
+
+    if !uv_installed && uv.install_astral_sh.unwrap_or_default() {
+        println!("Downloading uv installer … ");
+
+        let body = reqwest::blocking::get("https://astral.sh/uv/install.sh")?.text()?;
+
+        let cache_path = get_cache_path();
+
+        let mut file = std::fs::File::create(cache_path.clone() + "/uv_install.sh")?;
+        use std::io::Write;
+        file.write_all(body.as_bytes())?;
+
+        println!("Installing uv … ");
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("sh")
+        .arg(cache_path + "/uv_install.sh")
+        .status()?;
+        println!("==============================================================================");
+    } else {
+        println!("uv already installed");
+    }
+
+    Ok(())
+}
+
+fn setup_ghostty(ghostty: &Ghostty) -> Result<(), Box<dyn std::error::Error>> {
+    let ghostty_installed = std::process::Command::new("which")
+    .arg("ghostty")
+    .stdout(std::process::Stdio::null())
+    .stderr(std::process::Stdio::null())
+    .status()?
+    .success();
+
+    if !ghostty_installed && ghostty.install_ghosty_from_ghostty_ubuntu.unwrap_or_default() {
+        println!("Downloading ghostty deb package installer … ");
+
+        let body = reqwest::blocking::get("https://raw.githubusercontent.com/mkasberg/ghostty-ubuntu/HEAD/install.sh")?.text()?;
+
+        let cache_path = get_cache_path();
+
+        let mut file = std::fs::File::create(cache_path.clone() + "/ghostty_install.sh")?;
+        use std::io::Write;
+        file.write_all(body.as_bytes())?;
+
+        println!("Installing ghostty … ");
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("bash")
+        .arg(cache_path + "/ghostty_install.sh")
+        .status()?;
+        println!("==============================================================================");
+    } else {
+        println!("ghostty already installed");
+    }
+
+    Ok(())
+}
+
+/* Install Signal desktop according to the Signal desktop installation instruction for Ubuntu:
+
+  # NOTE: These instructions only work for 64-bit Debian-based
+  # Linux distributions such as Ubuntu, Mint etc.
+
+  # 1. Install our official public software signing key:
+  wget -O- https://updates.signal.org/desktop/apt/keys.asc | gpg --dearmor > signal-desktop-keyring.gpg;
+  cat signal-desktop-keyring.gpg | sudo tee /usr/share/keyrings/signal-desktop-keyring.gpg > /dev/null
+
+  # 2. Add our repository to your list of repositories:
+  wget -O signal-desktop.sources https://updates.signal.org/static/desktop/apt/signal-desktop.sources;
+  cat signal-desktop.sources | sudo tee /etc/apt/sources.list.d/signal-desktop.sources > /dev/null
+
+  # 3. Update your package database and install Signal:
+  sudo apt update && sudo apt install signal-desktop
+*/
+fn setup_signal(signal: &Signal) -> Result<(), Box<dyn std::error::Error>> {
+    let signal_installed = std::process::Command::new("which")
+    .arg("signal-desktop")
+    .stdout(std::process::Stdio::null())
+    .stderr(std::process::Stdio::null())
+    .status()?
+    .success();
+
+    if !signal_installed && signal.install_signal_desktop_ubuntu.unwrap_or_default() {
+        println!("Downloading Signal desktop package repository sources … ");
+
+        let body = reqwest::blocking::get("https://updates.signal.org/static/desktop/apt/signal-desktop.sources")?.text()?;
+
+        let cache_path = get_cache_path();
+
+        let mut file = std::fs::File::create(cache_path.clone() + "/signal_desktop_repository.source")?;
+        use std::io::Write;
+        file.write_all(body.as_bytes())?;
+
+        println!("Downloading Signal desktop package repository key … ");
+        let body = reqwest::blocking::get("https://updates.signal.org/desktop/apt/keys.asc")?.text()?;
+        let cache_path = get_cache_path();
+        let mut file = std::fs::File::create(cache_path.clone() + "/signal_desktop_repository_keys.asc")?;
+        file.write_all(body.as_bytes())?;
+
+        println!("Deamor Repository key … ");
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("bash")
+        .arg("-c")
+        .arg("echo ".to_string() + &cache_path + "/signal_desktop_repository_keys.asc | gpg --deamor > " + &cache_path + "/signal_desktop_repository_keyring.gpg")
+        .status()?;
+        println!("==============================================================================");
+
+        println!("Installing Signal desktop repository sources … ");
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("bash")
+        .arg("-c")
+        .arg("sudo mv ".to_string() + &cache_path + "/signal_desktop_repository.source /etc/apt/sources.list.d/signal-desktop.sources")
+        .status()?;
+        println!("==============================================================================");
+
+        println!("Installing Signal desktop repository key … ");
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("bash")
+        .arg("-c")
+        .arg("sudo mv ".to_string() + &cache_path + "/signal_desktop_repository_keyring.gpg /usr/share/keyrings/signal-desktop-keyring.gpg")
+        .status()?;
+        println!("==============================================================================");
+
+        println!("Installing Signal desktop … ");
+
+        println!("==============================================================================");
+        let _status = std::process::Command::new("bash")
+        .arg("-c")
+        .arg("sudo apt update && sudo apt install signal-desktop")
+        .status()?;
+        println!("==============================================================================");
+    } else {
+        println!("Signal desktop already installed");
+    }
+
+    Ok(())
+}
+
+fn get_username() -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(user) = std::env::var("USER") && !user.is_empty() {
+        return Ok(user);
+    }
+    let output = std::process::Command::new("id")
+        .arg("-un")
+        .output()?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+fn is_valid_alias_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || "'\"=$`/\\;&|<>()".contains(c))
+}
+
+fn setup_login_shell(login_shell: &str) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Setting up login shell {} … ", login_shell);
+
+    let shell_name = login_shell.rsplit_once("/").map(|(_, name)| name).unwrap_or(login_shell);
+    let shell_available = if login_shell.starts_with('/') { path_exists(login_shell) } else { command_exists(shell_name)? };
+    if !shell_available {
+        println!();
+        install_packages(vec![shell_name.to_string()], false)?;
+    }
+
+    let shell_path = if login_shell.starts_with('/') {
+        login_shell.to_string()
+    } else {
+        let output = std::process::Command::new("which")
+            .arg(shell_name)
+            .output()?;
+        String::from_utf8(output.stdout)?.trim().to_owned()
+    };
+    if shell_path.is_empty() || !path_exists(&shell_path) {
+        println!("ERROR\nShell {} is not installed and could not be installed", login_shell);
+        return Ok(());
+    }
+
+    let shells = std::fs::read_to_string("/etc/shells").unwrap_or_default();
+    if !shells.lines().any(|line| line.trim() == shell_path) {
+        println!();
+        println!("Adding {} to /etc/shells … ", shell_path);
+        println!("==============================================================================");
+        let _status = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!("echo {} | sudo tee -a /etc/shells > /dev/null", shell_single_quote(&shell_path)))
+            .status()?;
+        println!("==============================================================================");
+    }
+
+    let output = std::process::Command::new("getent")
+        .arg("passwd")
+        .arg(get_username()?)
+        .output()?;
+    let passwd_entry = String::from_utf8(output.stdout)?;
+    let current_shell = passwd_entry.trim().split(':').nth(6).unwrap_or_default();
+
+    if current_shell == shell_path {
+        println!("Already set up");
+        return Ok(());
+    }
+
+    println!();
+    println!("Changing login shell from {} to {} … ", current_shell, shell_path);
+    println!("==============================================================================");
+    let _status = std::process::Command::new("chsh")
+        .arg("-s")
+        .arg(&shell_path)
+        .status()?;
+    println!("==============================================================================");
+
+    Ok(())
+}
+
+const MANAGED_FILE_HEADER: &str = "# Managed by localsetup. Changes to this file will be overwritten.\n";
+
+fn setup_shell_rc(shell_name: &str, shell: &Shell) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Setting up shell aliases and environment for {} … ", shell_name);
+
+    let mut content = MANAGED_FILE_HEADER.to_string();
+    let mut errors = Vec::new();
+
+    for (name, value) in shell.env.iter().flatten() {
+        if !is_valid_variable_name(name) {
+            errors.push(format!("Invalid environment variable name '{}'", name));
+            continue;
+        }
+        let fish_quote = |value: &str| format!("\"{}\"", value.replace("\\", "\\\\").replace("\"", "\\\""));
+        if shell_name == "fish" && name.ends_with("PATH") {
+            // fish keeps PATH-like variables as lists: every directory is an element and "$PATH" needs to stay unquoted,
+            // otherwise the list would be joined with spaces
+            let elements = value.split(':').filter(|element| !element.is_empty()).map(|element| {
+                match element.strip_prefix('$').map(|variable| variable.trim_start_matches('{').trim_end_matches('}')) {
+                    Some(variable) if is_valid_variable_name(variable) => format!("${}", variable),
+                    _ => fish_quote(element),
+                }
+            }).collect::<Vec<String>>();
+            content += &format!("set -gx {} {}\n", name, elements.join(" "));
+        } else if shell_name == "fish" {
+            content += &format!("set -gx {} {}\n", name, fish_quote(value));
+        } else {
+            content += &format!("export {}={}\n", name, shell_double_quote(value));
+        }
+    }
+
+    for (name, value) in shell.aliases.iter().flatten() {
+        if !is_valid_alias_name(name) {
+            errors.push(format!("Invalid alias name '{}'", name));
+            continue;
+        }
+        if shell_name == "fish" {
+            let value = value.replace("\\", "\\\\").replace("'", "\\'");
+            content += &format!("alias {} '{}'\n", name, value);
+        } else {
+            content += &format!("alias {}={}\n", name, shell_single_quote(value));
+        }
+    }
+
+    // fish reads everything in conf.d on its own, all other shells need the file sourced from their rc file
+    let (managed_file, rc_file) = match shell_name {
+        "fish" => (get_config_path() + "/fish/conf.d/localsetup.fish", None),
+        "zsh" => (get_config_path() + "/localsetup/shell.sh", Some(get_home() + "/.zshrc")),
+        "bash" => (get_config_path() + "/localsetup/shell.sh", Some(get_home() + "/.bashrc")),
+        _ => (get_config_path() + "/localsetup/shell.sh", Some(get_home() + "/.profile")),
+    };
+
+    let mut changed = write_file_if_changed(&managed_file, &content)?;
+    if let Some(rc_file) = rc_file {
+        let source_line = format!("[ -f {0} ] && . {0} # added by localsetup", shell_double_quote(&managed_file));
+        changed |= ensure_line_in_file(&rc_file, &source_line)?;
+    }
+
+    if changed {
+        println!("done");
+        println!("Open a new shell to use the updated aliases and environment");
+    } else {
+        println!("Already set up");
+    }
+
+    for error in errors {
+        println!("Error! {}", error);
+    }
+
+    Ok(())
+}
+
+fn setup_shell(shell: &Shell) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(login_shell) = shell.login_shell.as_ref() {
+        setup_login_shell(login_shell)?;
+    }
+
+    if shell.aliases.is_some() || shell.env.is_some() {
+        let shell_path = shell.login_shell.clone()
+            .or(std::env::var("SHELL").ok())
+            .unwrap_or("bash".to_string());
+        let shell_name = shell_path.rsplit_once("/").map(|(_, name)| name).unwrap_or(&shell_path);
+        setup_shell_rc(shell_name, shell)?;
+    }
+
+    Ok(())
+}
+
+fn validate_environment_variable(name: &str, value: &str) -> Result<(), String> {
+    if !is_valid_variable_name(name) {
+        return Err(format!("Invalid environment variable name '{}'", name));
+    }
+    // environment.d only expands $VARIABLE and ${VARIABLE}, so anything a shell would interpret beyond that would
+    // give different values in graphical sessions and in login shells
+    if value.contains('\n') || value.contains('\0') {
+        return Err(format!("Value of environment variable {} must not contain line breaks", name));
+    }
+    if value.contains('`') || value.contains("$(") {
+        return Err(format!("Value of environment variable {} must not contain command substitutions", name));
+    }
+    Ok(())
+}
+
+fn setup_environment(environment: &std::collections::BTreeMap<String, String>) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Setting up environment variables … ");
+
+    let mut environment_d = MANAGED_FILE_HEADER.to_string();
+    let mut profile = MANAGED_FILE_HEADER.to_string();
+    let mut errors = Vec::new();
+
+    for (name, value) in environment {
+        if let Err(error) = validate_environment_variable(name, value) {
+            errors.push(error);
+            continue;
+        }
+        let value = value.replace("\\", "\\\\").replace("\"", "\\\"");
+        environment_d += &format!("{}=\"{}\"\n", name, value);
+        profile += &format!("export {}=\"{}\"\n", name, value);
+    }
+
+    let environment_d_changed = write_file_if_changed(&(get_config_path() + "/environment.d/60-localsetup.conf"), &environment_d)?;
+
+    let profile_file = get_config_path() + "/localsetup/environment.sh";
+    let source_line = format!("[ -f {0} ] && . {0} # added by localsetup", shell_double_quote(&profile_file));
+    let mut profile_changed = write_file_if_changed(&profile_file, &profile)?;
+    profile_changed |= ensure_line_in_file(&(get_home() + "/.profile"), &source_line)?;
+    // bash and zsh do not read ~/.profile if they have their own login file
+    for login_file in [get_home() + "/.bash_profile", get_home() + "/.zprofile"] {
+        if path_exists(&login_file) {
+            profile_changed |= ensure_line_in_file(&login_file, &source_line)?;
+        }
+    }
+
+    if environment_d_changed || profile_changed {
+        println!("done");
+    } else {
+        println!("Already set up");
+    }
+    if environment_d_changed {
+        println!("Log out and in again to update the environment of graphical sessions and systemd user services");
+    }
+    if profile_changed {
+        println!("Start a new login shell (or run `. ~/.profile`) to update the environment of shells");
+    }
+
+    for error in errors {
+        println!("Error! {}", error);
+    }
+
+    Ok(())
+}
+
+fn systemd_user_show(unit: &str) -> Result<std::collections::BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("systemctl")
+        .arg("--user")
+        .arg("show")
+        .arg("--property=ActiveState,UnitFileState")
+        .arg(unit)
+        .output()?;
+    let properties = String::from_utf8(output.stdout)?
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Ok(properties)
+}
 
-                use std::io::Write;
-                println!("Downloading Mozilla signing key … ");
-                let body = reqwest::blocking::get("https://packages.mozilla.org/apt/repo-signing-key.gpg")?.text()?;
-                let cache_path = get_cache_path();
-                let mut file = std::fs::File::create(cache_path.clone() + "/mozilla_repo_signing_key.gpg")?;
-                file.write_all(body.as_bytes())?;
+fn systemctl_user(action: &str, unit: &str) -> Result<(), Box<dyn std::error::Error>> {
+    println!("==============================================================================");
+    let status = std::process::Command::new("systemctl")
+        .arg("--user")
+        .arg(action)
+        .arg(unit)
+        .status()?;
+    println!("==============================================================================");
+    if !status.success() {
+        println!("Error! systemctl --user {} {} failed", action, unit);
+    }
+    Ok(())
+}
 
-                print!("Checking Mozilla signing key integrity … ");
-                let output = std::process::Command::new("gpg")
-                    .arg("gpg")
-                    .arg("-n")
-                    .arg("-q")
-                    .arg("--import")
-                    .arg("--import-options")
-                    .arg("import-show")
-                    .arg(cache_path.clone() + "/mozilla_repo_signing_key.gpg")
-                    .output()?;
-                if String::from_utf8_lossy(&output.stdout).contains("35BAA0B33E9EB396F59CA838C0BA5CE6DC6315A3") {
-                    println!("Ok");
-                } else {
-                    println!("ERROR: SIGNING KEY MISSMATCH. ABORTING.");
-                    panic!("ERROR: SIGNING KEY MISSMATCH. ABORTING.");
-                }
+fn setup_linger() -> Result<(), Box<dyn std::error::Error>> {
+    print!("Enabling lingering for user services … ");
 
-                println!("Installing Mozilla repository key … ");
+    let user = get_username()?;
+    let output = std::process::Command::new("loginctl")
+        .arg("show-user")
+        .arg("--property=Linger")
+        .arg(&user)
+        .output()?;
+    if String::from_utf8(output.stdout)?.trim() == "Linger=yes" {
+        println!("Already set up");
+        return Ok(());
+    }
 
-                println!("==============================================================================");
-                let _status = std::process::Command::new("bash")
-                    .arg("-c")
-                    .arg("sudo mv ".to_string() + &cache_path + "/mozilla_repo_signing_key.gpg /etc/apt/keyrings/packages.mozilla.org.asc")
-                    .status()?;
-                println!("==============================================================================");
+    println!();
+    println!("==============================================================================");
+    let _status = std::process::Command::new("loginctl")
+        .arg("enable-linger")
+        .arg(&user)
+        .status()?;
+    println!("==============================================================================");
+    Ok(())
+}
 
-                create_file_with_content_if_not_exists_root(FIREFOX_MOZILLA_REPOSITORY_FILENAME, FIREFOX_MOZILLA_REPOSITORY_FILE_CONTENT)?;
+fn setup_systemd_user_units(units: &[SystemdUserUnit]) -> Result<(), Box<dyn std::error::Error>> {
+    let unit_path = get_config_path() + "/systemd/user";
+    let mut changed_units = Vec::new();
 
-                println!("==============================================================================");
-                let _status = std::process::Command::new("sudo")
-                    .arg("apt")
-                    .arg("update")
-                    .status()?;
-                println!("==============================================================================");
+    for unit in units {
+        if unit.name.contains('/') || !unit.name.contains('.') {
+            println!("Error! Invalid systemd unit name '{}'", unit.name);
+            continue;
+        }
 
-                println!("==============================================================================");
-                let _status = std::process::Command::new("sudo")
-                    .arg("apt")
-                    .arg("install")
-                    .arg("--yes")
-                    .arg("firefox")
-                    .status()?;
-                println!("==============================================================================");
-            } else {
-                println!("Firefox is already installed");
+        let content = match (unit.content.as_ref(), unit.file.as_ref()) {
+            (Some(content), _) => content.clone(),
+            (None, Some(file)) => match std::fs::read_to_string(expand_home(file)) {
+                Ok(content) => content,
+                Err(err) => {
+                    println!("Error! Could not read {} for systemd unit {}: {}", file, unit.name, err);
+                    continue;
+                }
+            },
+            (None, None) => continue,
+        };
+
+        print!("Writing systemd user unit {} … ", unit.name);
+        if write_file_if_changed(&format!("{}/{}", unit_path, unit.name), &content)? {
+            println!("done");
+            changed_units.push(unit.name.clone());
+        } else {
+            println!("Already up to date");
+        }
+    }
+
+    if !changed_units.is_empty() {
+        println!("Reloading systemd user units … ");
+        println!("==============================================================================");
+        let _status = std::process::Command::new("systemctl")
+            .arg("--user")
+            .arg("daemon-reload")
+            .status()?;
+        println!("==============================================================================");
+    }
+
+    for unit in units {
+        let properties = systemd_user_show(&unit.name)?;
+        let unit_file_state = properties.get("UnitFileState").map(String::as_str).unwrap_or_default();
+        let active = properties.get("ActiveState").is_some_and(|state| state == "active");
+        let changed = changed_units.contains(&unit.name);
+
+        match unit.enable {
+            Some(true) if unit_file_state != "enabled" => {
+                println!("Enabling systemd user unit {} … ", unit.name);
+                systemctl_user("enable", &unit.name)?;
             }
+            Some(false) if unit_file_state == "enabled" => {
+                println!("Disabling systemd user unit {} … ", unit.name);
+                systemctl_user("disable", &unit.name)?;
+            }
+            _ => {}
+        }
 
-            print!("Ensuring Firefox will be installed from PPA … ");
-            if path_exists(FIREFOX_MOZILLA_FILENAME) {
-                println!("already ensured");
-            } else {
-                create_file_with_content_if_not_exists_root(FIREFOX_MOZILLA_FILENAME, FIREFOX_MOZILLA_FILE_CONTENT)?;
-                println!("done");
+        match unit.running {
+            Some(true) if !active => {
+                println!("Starting systemd user unit {} … ", unit.name);
+                systemctl_user("start", &unit.name)?;
+            }
+            Some(false) if active => {
+                println!("Stopping systemd user unit {} … ", unit.name);
+                systemctl_user("stop", &unit.name)?;
+            }
+            Some(true) | None if active && changed && unit.restart_on_change.unwrap_or(true) => {
+                println!("Restarting systemd user unit {} … ", unit.name);
+                systemctl_user("restart", &unit.name)?;
             }
+            _ => {}
         }
     }
+
+    if units.iter().any(|unit| unit.linger.unwrap_or_default()) {
+        setup_linger()?;
+    }
+
     Ok(())
 }
 
-fn ubuntu_specifics(ubuntu: &Ubuntu) -> Result<(), Box<dyn std::error::Error>> {
-    if ubuntu.remove_snap_and_install_firefox_ppa.unwrap_or_default() {
-        ubuntu_remove_snap_and_install_firefox_ppa_or_mozilla(PpaOrMozilla::Ppa, ubuntu.remove_snap_and_install_firefox_ppa_yes_delete_my_bookmarks_and_everything.unwrap_or_default())?;
+fn session_bus_available() -> bool {
+    if std::env::var("DBUS_SESSION_BUS_ADDRESS").is_ok_and(|address| !address.is_empty()) {
+        return true;
     }
+    std::env::var("XDG_RUNTIME_DIR").is_ok_and(|runtime_dir| path_exists(&(runtime_dir + "/bus")))
+}
 
-    if ubuntu.remove_snap_and_install_firefox_mozilla.unwrap_or_default() {
-        ubuntu_remove_snap_and_install_firefox_ppa_or_mozilla(PpaOrMozilla::Mozilla, ubuntu.remove_snap_and_install_firefox_ppa_yes_delete_my_bookmarks_and_everything.unwrap_or_default())?;
+/// Renders a string the way GLib prints it (and so `gsettings get` shows it), so values can be compared.
+fn gvariant_string(string: &str) -> String {
+    let quote = if string.contains('\'') { '"' } else { '\'' };
+    let mut rendered = quote.to_string();
+    for c in string.chars() {
+        match c {
+            '\\' => rendered += "\\\\",
+            c if c == quote => rendered += &format!("\\{}", c),
+            '\u{7}' => rendered += "\\a",
+            '\u{8}' => rendered += "\\b",
+            '\u{c}' => rendered += "\\f",
+            '\n' => rendered += "\\n",
+            '\r' => rendered += "\\r",
+            '\t' => rendered += "\\t",
+            '\u{b}' => rendered += "\\v",
+            c if c.is_control() => rendered += &format!("\\u{:04x}", c as u32),
+            c => rendered.push(c),
+        }
     }
-
-    Ok(())
+    rendered.push(quote);
+    rendered
 }
 
-fn setup_rustup(rustup: &Rustup) -> Result<(), Box<dyn std::error::Error>> {
-    let rustup_installed = std::process::Command::new("which")
-        .arg("rustup")
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .status()?
-        .success();
+/// Renders a value from the Setupfile in the GVariant text format, as used by `gsettings`.
+fn toml_to_gvariant(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(string) => Some(gvariant_string(string)),
+        toml::Value::Integer(integer) => Some(integer.to_string()),
+        toml::Value::Float(float) => Some(format!("{:?}", float)),
+        toml::Value::Boolean(boolean) => Some(boolean.to_string()),
+        toml::Value::Array(array) => {
+            let items = array.iter().map(toml_to_gvariant).collect::<Option<Vec<String>>>()?;
+            Some(format!("[{}]", items.join(", ")))
+        }
+        _ => None,
+    }
+}
 
-    let rust_installed = std::process::Command::new("which")
-        .arg("rustup")
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .status()?
-        .success();
+/// Removes the type annotation `gsettings get` adds to some values (e.g. `uint32 5` or `@as []`).
+fn strip_gvariant_type(value: &str) -> &str {
+    const TYPES: [&str; 8] = ["byte ", "int16 ", "uint16 ", "int32 ", "uint32 ", "int64 ", "uint64 ", "double "];
+    for prefix in TYPES {
+        if let Some(value) = value.strip_prefix(prefix) {
+            return value;
+        }
+    }
+    if value.starts_with('@') && let Some((_, value)) = value.split_once(' ') {
+        return value;
+    }
+    value
+}
 
-    if !rust_installed && rustup.install_rust.unwrap_or_default() {
-        if !rustup_installed {
-            println!("Installing rustup … ");
+fn setup_gsettings(gsettings: &std::collections::BTreeMap<String, std::collections::BTreeMap<String, toml::Value>>) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Setting up GNOME settings … ");
+    if !command_exists("gsettings")? {
+        println!("Skipping, gsettings is not installed");
+        return Ok(());
+    }
+    if !session_bus_available() {
+        println!("Skipping, no desktop session running");
+        return Ok(());
+    }
+    println!();
 
-            let body = reqwest::blocking::get("https://sh.rustup.rs")?.text()?;
+    let mut schemas = Vec::new();
+    for list in ["list-schemas", "list-relocatable-schemas"] {
+        let output = std::process::Command::new("gsettings")
+            .arg(list)
+            .output()?;
+        schemas.extend(String::from_utf8(output.stdout)?.lines().map(str::to_owned));
+    }
 
-            let cache_path = get_cache_path();
+    for (schema_and_path, keys) in gsettings {
+        // Relocatable schemas are given as `schema:/path/`
+        let schema = schema_and_path.split_once(':').map(|(schema, _)| schema).unwrap_or(schema_and_path);
+        if !schemas.iter().any(|s| s == schema) {
+            println!("Skipping gsettings schema {}, it is not installed", schema);
+            continue;
+        }
 
-            let mut file = std::fs::File::create(cache_path.clone() + "/rustup.sh")?;
-            use std::io::Write;
-            file.write_all(body.as_bytes())?;
+        for (key, value) in keys {
+            let Some(value) = toml_to_gvariant(value) else {
+                println!("Error! Unsupported value for gsettings {} {}", schema_and_path, key);
+                continue;
+            };
 
-            println!("Installing rust … ");
+            let output = std::process::Command::new("gsettings")
+                .arg("get")
+                .arg(schema_and_path)
+                .arg(key)
+                .output()?;
+            if !output.status.success() {
+                println!("Error! gsettings key {} {} does not exist", schema_and_path, key);
+                continue;
+            }
+            let current_value = String::from_utf8(output.stdout)?;
+            if strip_gvariant_type(current_value.trim()) == value {
+                continue;
+            }
 
-            println!("==============================================================================");
-            let _status = std::process::Command::new("sh")
-                .arg(cache_path + "/rustup.sh")
-                .arg("-y")
+            println!("Setting gsettings {} {} to {}", schema_and_path, key, value);
+            let _status = std::process::Command::new("gsettings")
+                .arg("set")
+                .arg(schema_and_path)
+                .arg(key)
+                .arg(&value)
                 .status()?;
-            println!("==============================================================================");
-        } else {
-            println!("Rustup already installed");
-            println!("Installing rust …");
+        }
+    }
 
-            println!("==============================================================================");
-            let _status = std::process::Command::new("rustup")
-                .arg("toolchain")
-                .arg("install")
-                .arg("stable")
-                .status()?;
-            println!("==============================================================================");
+    Ok(())
+}
+
+/// Parses a keyfile as written by `dconf dump` into (section, key) -> value.
+fn parse_keyfile(content: &str) -> std::collections::BTreeMap<(String, String), String> {
+    let mut entries = std::collections::BTreeMap::new();
+    let mut section = String::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            section = name.to_string();
+        } else if let Some((key, value)) = line.split_once('=') {
+            entries.insert((section.clone(), key.trim().to_string()), value.trim().to_string());
+        }
+    }
+    entries
+}
+
+fn setup_dconf(dconf: &[Dconf]) -> Result<(), Box<dyn std::error::Error>> {
+    if !command_exists("dconf")? || !session_bus_available() {
+        println!("Skipping dconf settings, dconf is not installed or no desktop session running");
+        return Ok(());
     }
 
-    if rustup_installed && rust_installed && rustup.update_rust.unwrap_or_default() {
-        println!("Rustup already installed");
-        println!("Rust already installed");
-        println!("Updating rust …");
+    for entry in dconf {
+        print!("Loading dconf settings at {} … ", entry.path);
+        if !entry.path.starts_with('/') || !entry.path.ends_with('/') {
+            println!("ERROR\nThe dconf path must start and end with '/'");
+            continue;
+        }
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("rustup")
-            .arg("update")
-            .status()?;
-        println!("==============================================================================");
+        let content = match (entry.content.as_ref(), entry.file.as_ref()) {
+            (Some(content), _) => content.clone(),
+            (None, Some(file)) => match std::fs::read_to_string(expand_home(file)) {
+                Ok(content) => content,
+                Err(err) => {
+                    println!("ERROR\nCould not read {}: {}", file, err);
+                    continue;
+                }
+            },
+            (None, None) => {
+                println!("ERROR\nEither content or file needs to be given");
+                continue;
+            }
+        };
+
+        let output = std::process::Command::new("dconf")
+            .arg("dump")
+            .arg(&entry.path)
+            .output()?;
+        let current = parse_keyfile(&String::from_utf8(output.stdout)?);
+        let wanted = parse_keyfile(&content);
+
+        if wanted.iter().all(|(key, value)| current.get(key) == Some(value)) {
+            println!("Already set up");
+            continue;
+        }
+
+        let mut child = std::process::Command::new("dconf")
+            .arg("load")
+            .arg(&entry.path)
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(content.as_bytes())?;
+        if child.wait()?.success() {
+            println!("done");
+        } else {
+            println!("ERROR");
+        }
     }
 
     Ok(())
 }
 
-fn setup_uv(uv: &Uv) -> Result<(), Box<dyn std::error::Error>> {
-    let uv_installed = std::process::Command::new("which")
-    .arg("uv")
-    .stdout(std::process::Stdio::null())
-    .stderr(std::process::Stdio::null())
-    .status()?
-    .success();
+fn current_desktop_is(desktop: &str) -> bool {
+    std::env::var("XDG_CURRENT_DESKTOP")
+        .is_ok_and(|current| current.split(':').any(|name| name.eq_ignore_ascii_case(desktop)))
+}
 
-    if !uv_installed && uv.install_astral_sh.unwrap_or_default() {
-        println!("Downloading uv installer … ");
+fn toml_to_kconfig(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(string) => Some(string.clone()),
+        toml::Value::Integer(integer) => Some(integer.to_string()),
+        toml::Value::Float(float) => Some(float.to_string()),
+        toml::Value::Boolean(boolean) => Some(boolean.to_string()),
+        toml::Value::Array(array) => {
+            let items = array.iter().map(toml_to_kconfig).collect::<Option<Vec<String>>>()?;
+            Some(items.join(","))
+        }
+        _ => None,
+    }
+}
 
-        let body = reqwest::blocking::get("https://astral.sh/uv/install.sh")?.text()?;
+fn setup_kde(kde: &Kde) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Setting up KDE settings … ");
+    if !current_desktop_is("KDE") {
+        println!("Skipping, not running KDE Plasma");
+        return Ok(());
+    }
 
-        let cache_path = get_cache_path();
+    let (kreadconfig, kwriteconfig) = if command_exists("kwriteconfig6")? {
+        ("kreadconfig6", "kwriteconfig6")
+    } else if command_exists("kwriteconfig5")? {
+        ("kreadconfig5", "kwriteconfig5")
+    } else {
+        println!("Skipping, kwriteconfig is not installed");
+        return Ok(());
+    };
+    println!();
+
+    let mut changed_files = Vec::new();
+    for (file, entries) in &kde.files {
+        for (group_and_key, value) in entries {
+            // Nested groups are given as `Group/Subgroup/Key`
+            let Some((groups, key)) = group_and_key.rsplit_once('/') else {
+                println!("Error! KDE setting '{}' in {} needs to be given as 'group/key'", group_and_key, file);
+                continue;
+            };
+            let is_bool = matches!(value, toml::Value::Boolean(_));
+            let Some(value) = toml_to_kconfig(value) else {
+                println!("Error! Unsupported value for KDE setting {} in {}", group_and_key, file);
+                continue;
+            };
 
-        let mut file = std::fs::File::create(cache_path.clone() + "/uv_install.sh")?;
-        use std::io::Write;
-        file.write_all(body.as_bytes())?;
+            let mut read = std::process::Command::new(kreadconfig);
+            read.arg("--file").arg(file);
+            for group in groups.split('/') {
+                read.arg("--group").arg(group);
+            }
+            let output = read.arg("--key").arg(key).output()?;
+            if String::from_utf8(output.stdout)?.trim_end_matches('\n') == value {
+                continue;
+            }
 
-        println!("Installing uv … ");
+            println!("Setting KDE setting {} in {} to {}", group_and_key, file, value);
+            let mut write = std::process::Command::new(kwriteconfig);
+            write.arg("--file").arg(file);
+            for group in groups.split('/') {
+                write.arg("--group").arg(group);
+            }
+            write.arg("--key").arg(key);
+            if is_bool {
+                write.arg("--type").arg("bool");
+            }
+            let _status = write.arg(&value).status()?;
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("sh")
-        .arg(cache_path + "/uv_install.sh")
-        .status()?;
-        println!("==============================================================================");
-    } else {
-        println!("uv already installed");
+            if !changed_files.contains(file) {
+                changed_files.push(file.clone());
+            }
+        }
+    }
+
+    if !kde.reconfigure.unwrap_or_default() {
+        return Ok(());
+    }
+
+    for file in changed_files {
+        let signal: &[&str] = match file.as_str() {
+            "kwinrc" => &["/KWin", "org.kde.KWin.reloadConfig"],
+            "kdeglobals" => &["/KGlobalSettings", "org.kde.KGlobalSettings.notifyChange", "int32:0", "int32:0"],
+            _ => {
+                println!("Changes to {} will be applied after logging out and in again", file);
+                continue;
+            }
+        };
+        println!("Reconfiguring KDE for changes to {} … ", file);
+        let _status = std::process::Command::new("dbus-send")
+            .arg("--session")
+            .arg("--type=signal")
+            .args(signal)
+            .status()?;
     }
 
     Ok(())
 }
 
-fn setup_ghostty(ghostty: &Ghostty) -> Result<(), Box<dyn std::error::Error>> {
-    let ghostty_installed = std::process::Command::new("which")
-    .arg("ghostty")
-    .stdout(std::process::Stdio::null())
-    .stderr(std::process::Stdio::null())
-    .status()?
-    .success();
-
-    if !ghostty_installed && ghostty.install_ghosty_from_ghostty_ubuntu.unwrap_or_default() {
-        println!("Downloading ghostty deb package installer … ");
+const FONT_MARKER_FILENAME: &str = ".installed_by_localsetup";
 
-        let body = reqwest::blocking::get("https://raw.githubusercontent.com/mkasberg/ghostty-ubuntu/HEAD/install.sh")?.text()?;
+fn install_font(font: &Font, fonts_dir: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    print!("Installing font {} … ", font.name);
 
-        let cache_path = get_cache_path();
+    if font.name.is_empty() || font.name.contains('/') || font.name.starts_with('.') {
+        println!("ERROR\nInvalid font name '{}'", font.name);
+        return Ok(false);
+    }
+    let family_dir = format!("{}/{}", fonts_dir, font.name);
+    // The marker contains the checksum of the installed archive, so a changed url or sha256 installs the font again
+    let marker = std::fs::read_to_string(format!("{}/{}", family_dir, FONT_MARKER_FILENAME));
+    match marker {
+        Ok(installed_sha256) if installed_sha256.trim().eq_ignore_ascii_case(font.sha256.trim()) => {
+            println!("Already installed");
+            return Ok(false);
+        }
+        Err(_) if path_exists(&family_dir) => {
+            println!("Already installed (not by localsetup)");
+            return Ok(false);
+        }
+        _ => println!(),
+    }
 
-        let mut file = std::fs::File::create(cache_path.clone() + "/ghostty_install.sh")?;
-        use std::io::Write;
-        file.write_all(body.as_bytes())?;
+    println!("Downloading {} … ", font.url);
+    let archive = match reqwest::blocking::get(&font.url).and_then(|response| response.error_for_status()).and_then(|response| response.bytes()) {
+        Ok(archive) => archive,
+        Err(err) => {
+            println!("Error! Could not download font {}: {}", font.name, err);
+            return Ok(false);
+        }
+    };
 
-        println!("Installing ghostty … ");
+    use sha2::Digest;
+    let sha256 = format!("{:x}", sha2::Sha256::digest(&archive));
+    if !sha256.eq_ignore_ascii_case(font.sha256.trim()) {
+        println!("Error! Checksum mismatch for font {}: expected {}, got {}", font.name, font.sha256, sha256);
+        return Ok(false);
+    }
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("bash")
-        .arg(cache_path + "/ghostty_install.sh")
-        .status()?;
-        println!("==============================================================================");
+    let cache_path = get_cache_path();
+    let extract_dir = format!("{}/fonts/{}", cache_path, font.name);
+    let _ = std::fs::remove_dir_all(&extract_dir);
+    std::fs::create_dir_all(&extract_dir)?;
+    let archive_path = format!("{}/fonts/{}.archive", cache_path, font.name);
+    std::fs::write(&archive_path, &archive)?;
+
+    let url = font.url.split(['?', '#']).next().unwrap_or_default().to_lowercase();
+    let status = if url.ends_with(".zip") {
+        std::process::Command::new("unzip")
+            .arg("-q")
+            .arg("-o")
+            .arg(&archive_path)
+            .arg("-d")
+            .arg(&extract_dir)
+            .status()?
     } else {
-        println!("ghostty already installed");
+        std::process::Command::new("tar")
+            .arg("-xf")
+            .arg(&archive_path)
+            .arg("-C")
+            .arg(&extract_dir)
+            .status()?
+    };
+    if !status.success() {
+        println!("Error! Could not extract font archive {}", font.url);
+        return Ok(false);
     }
 
-    Ok(())
-}
-
-/* Install Signal desktop according to the Signal desktop installation instruction for Ubuntu:
+    // Font files are collected next to the installed font first, so an older version is only replaced by a working one
+    let staging_dir = format!("{}/.{}.localsetup-new", fonts_dir, font.name);
+    let _ = std::fs::remove_dir_all(&staging_dir);
+    std::fs::create_dir_all(&staging_dir)?;
+    let mut installed = 0;
+    for file in find_files(std::path::Path::new(&extract_dir))? {
+        let Some(filename) = file.file_name().map(|name| name.to_string_lossy().into_owned()) else {
+            continue;
+        };
+        let extension = file.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+        if extension.as_deref() != Some("ttf") && extension.as_deref() != Some("otf") {
+            continue;
+        }
+        if let Some(patterns) = font.files.as_ref() && !patterns.iter().any(|pattern| glob_match(pattern, &filename)) {
+            continue;
+        }
+        std::fs::copy(&file, format!("{}/{}", staging_dir, filename))?;
+        installed += 1;
+    }
 
-  # NOTE: These instructions only work for 64-bit Debian-based
-  # Linux distributions such as Ubuntu, Mint etc.
+    let _ = std::fs::remove_dir_all(&extract_dir);
+    let _ = std::fs::remove_file(&archive_path);
 
-  # 1. Install our official public software signing key:
-  wget -O- https://updates.signal.org/desktop/apt/keys.asc | gpg --dearmor > signal-desktop-keyring.gpg;
-  cat signal-desktop-keyring.gpg | sudo tee /usr/share/keyrings/signal-desktop-keyring.gpg > /dev/null
+    if installed == 0 {
+        println!("Error! No matching font files found in {}", font.url);
+        std::fs::remove_dir_all(&staging_dir)?;
+        return Ok(false);
+    }
+    std::fs::write(format!("{}/{}", staging_dir, FONT_MARKER_FILENAME), font.sha256.trim())?;
+    if path_exists(&family_dir) {
+        std::fs::remove_dir_all(&family_dir)?;
+    }
+    std::fs::rename(&staging_dir, &family_dir)?;
+    println!("Installed {} font files for {}", installed, font.name);
 
-  # 2. Add our repository to your list of repositories:
-  wget -O signal-desktop.sources https://updates.signal.org/static/desktop/apt/signal-desktop.sources;
-  cat signal-desktop.sources | sudo tee /etc/apt/sources.list.d/signal-desktop.sources > /dev/null
+    Ok(true)
+}
 
-  # 3. Update your package database and install Signal:
-  sudo apt update && sudo apt install signal-desktop
-*/
-fn setup_signal(signal: &Signal) -> Result<(), Box<dyn std::error::Error>> {
-    let signal_installed = std::process::Command::new("which")
-    .arg("signal-desktop")
-    .stdout(std::process::Stdio::null())
-    .stderr(std::process::Stdio::null())
-    .status()?
-    .success();
+fn setup_fonts(fonts: &[Font]) -> Result<(), Box<dyn std::error::Error>> {
+    let fonts_dir = get_home() + "/.local/share/fonts";
+    let mut changed = false;
 
-    if !signal_installed && signal.install_signal_desktop_ubuntu.unwrap_or_default() {
-        println!("Downloading Signal desktop package repository sources … ");
+    for font in fonts {
+        changed |= install_font(font, &fonts_dir)?;
+    }
 
-        let body = reqwest::blocking::get("https://updates.signal.org/static/desktop/apt/signal-desktop.sources")?.text()?;
+    // Fonts installed by localsetup that are no longer in the Setupfile get removed again
+    if let Ok(entries) = std::fs::read_dir(&fonts_dir) {
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            if path.join(FONT_MARKER_FILENAME).exists() && !fonts.iter().any(|font| font.name == name) {
+                println!("Removing font {} … ", name);
+                std::fs::remove_dir_all(&path)?;
+                changed = true;
+            }
+        }
+    }
 
-        let cache_path = get_cache_path();
+    if changed {
+        println!("Updating font cache … ");
+        println!("==============================================================================");
+        let _status = std::process::Command::new("fc-cache")
+            .arg("-f")
+            .arg(&fonts_dir)
+            .status()?;
+        println!("==============================================================================");
+    }
 
-        let mut file = std::fs::File::create(cache_path.clone() + "/signal_desktop_repository.source")?;
-        use std::io::Write;
-        file.write_all(body.as_bytes())?;
+    Ok(())
+}
 
-        println!("Downloading Signal desktop package repository key … ");
-        let body = reqwest::blocking::get("https://updates.signal.org/desktop/apt/keys.asc")?.text()?;
-        let cache_path = get_cache_path();
-        let mut file = std::fs::File::create(cache_path.clone() + "/signal_desktop_repository_keys.asc")?;
-        file.write_all(body.as_bytes())?;
+fn desktop_file_exists(desktop_id: &str) -> bool {
+    let data_home = std::env::var("XDG_DATA_HOME")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or(get_home() + "/.local/share");
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or("/usr/local/share:/usr/share".to_string());
+
+    std::iter::once(data_home)
+        .chain(data_dirs.split(':').map(str::to_owned))
+        .chain([get_home() + "/.local/share/flatpak/exports/share", "/var/lib/flatpak/exports/share".to_string(), "/var/lib/snapd/desktop".to_string()])
+        .any(|data_dir| path_exists(&format!("{}/applications/{}", data_dir, desktop_id)))
+}
 
-        println!("Deamor Repository key … ");
+/// Sets `key=value` entries in one section of an ini-style file, keeping everything else as it is.
+fn set_ini_values(content: &str, section: &str, values: &std::collections::BTreeMap<String, String>) -> String {
+    let mut lines = Vec::new();
+    let mut remaining = values.clone();
+    let mut in_section = false;
+    // New keys go after the last line of the section, before the blank lines separating it from the next one
+    let mut section_end = 0;
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            if in_section {
+                let new_lines = remaining.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<String>>();
+                lines.splice(section_end..section_end, new_lines);
+                remaining.clear();
+            }
+            in_section = trimmed == format!("[{}]", section);
+        } else if in_section && let Some((key, _)) = trimmed.split_once('=') && let Some(value) = remaining.remove(key.trim()) {
+            lines.push(format!("{}={}", key.trim(), value));
+            section_end = lines.len();
+            continue;
+        }
+        lines.push(line.to_string());
+        if in_section && !trimmed.is_empty() {
+            section_end = lines.len();
+        }
+    }
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("bash")
-        .arg("-c")
-        .arg("echo ".to_string() + &cache_path + "/signal_desktop_repository_keys.asc | gpg --deamor > " + &cache_path + "/signal_desktop_repository_keyring.gpg")
-        .status()?;
-        println!("==============================================================================");
+    if !remaining.is_empty() {
+        let new_lines = remaining.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<String>>();
+        if in_section {
+            lines.splice(section_end..section_end, new_lines);
+        } else {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(format!("[{}]", section));
+            lines.extend(new_lines);
+        }
+    }
 
-        println!("Installing Signal desktop repository sources … ");
+    if lines.is_empty() {
+        return String::new();
+    }
+    lines.join("\n") + "\n"
+}
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("bash")
-        .arg("-c")
-        .arg("sudo mv ".to_string() + &cache_path + "/signal_desktop_repository.source /etc/apt/sources.list.d/signal-desktop.sources")
-        .status()?;
-        println!("==============================================================================");
+fn setup_default_apps(default_apps: &DefaultApps) -> Result<(), Box<dyn std::error::Error>> {
+    print!("Setting up default applications … ");
 
-        println!("Installing Signal desktop repository key … ");
+    let mut defaults = std::collections::BTreeMap::new();
+    if let Some(web_browser) = default_apps.web_browser.as_ref() {
+        for mime_type in ["x-scheme-handler/http", "x-scheme-handler/https", "text/html", "application/xhtml+xml"] {
+            defaults.insert(mime_type.to_string(), web_browser.clone());
+        }
+    }
+    defaults.extend(default_apps.mime.clone().unwrap_or_default());
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("bash")
-        .arg("-c")
-        .arg("sudo mv ".to_string() + &cache_path + "/signal_desktop_repository_keyring.gpg /usr/share/keyrings/signal-desktop-keyring.gpg")
-        .status()?;
-        println!("==============================================================================");
+    let mut errors = Vec::new();
+    defaults.retain(|mime_type, desktop_id| {
+        let exists = desktop_file_exists(desktop_id);
+        if !exists {
+            errors.push(format!("Application {} for {} is not installed", desktop_id, mime_type));
+        }
+        exists
+    });
+
+    let mut changed = false;
+    if !defaults.is_empty() {
+        let mimeapps_list = get_config_path() + "/mimeapps.list";
+        let content = std::fs::read_to_string(&mimeapps_list).unwrap_or_default();
+        changed |= write_file_if_changed(&mimeapps_list, &set_ini_values(&content, "Default Applications", &defaults))?;
+    }
 
-        println!("Installing Signal desktop … ");
+    // Terminal as used by xdg-terminal-exec, see https://gitlab.freedesktop.org/terminal-wg/specifications
+    if let Some(terminal) = default_apps.terminal.as_ref() {
+        if desktop_file_exists(terminal) {
+            let terminals_list = get_config_path() + "/xdg-terminals.list";
+            let existing = std::fs::read_to_string(&terminals_list).unwrap_or_default();
+            let content = std::iter::once(terminal.as_str())
+                .chain(existing.lines().filter(|line| line.trim() != terminal))
+                .collect::<Vec<&str>>()
+                .join("\n") + "\n";
+            changed |= write_file_if_changed(&terminals_list, &content)?;
+        } else {
+            errors.push(format!("Terminal {} is not installed", terminal));
+        }
+    }
 
-        println!("==============================================================================");
-        let _status = std::process::Command::new("bash")
-        .arg("-c")
-        .arg("sudo apt update && sudo apt install signal-desktop")
-        .status()?;
-        println!("==============================================================================");
+    if changed {
+        println!("done");
     } else {
-        println!("Signal desktop already installed");
+        println!("Already set up");
+    }
+
+    for error in errors {
+        println!("Error! {}", error);
     }
 
     Ok(())
@@ -1168,10 +3761,274 @@ fn update() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+const SCHEDULE_SERVICE_NAME: &str = "localsetup.service";
+const SCHEDULE_TIMER_NAME: &str = "localsetup.timer";
+
+fn schedule_enable(every: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if every.is_empty() || !every.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ') {
+        return args_error(&format!("Invalid interval '{}'. Use a systemd time span like '30min', '1h' or '1d'.", every));
+    }
+
+    let installed_binary = get_home() + "/.local/bin/localsetup";
+    let binary = if path_exists(&installed_binary) {
+        installed_binary
+    } else {
+        std::env::current_exe()?.to_string_lossy().into_owned()
+    };
+
+    let service = format!("[Unit]
+Description=Apply the localsetup Setupfile
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+Environment=LOCALSETUP_NON_INTERACTIVE=1
+Environment=GIT_TERMINAL_PROMPT=0
+Environment=\"GIT_SSH_COMMAND=ssh -o BatchMode=yes\"
+ExecStart={}
+", binary);
+
+    let timer = format!("[Unit]
+Description=Apply the localsetup Setupfile every {0}
+
+[Timer]
+OnBootSec=5min
+OnUnitActiveSec={0}
+
+[Install]
+WantedBy=timers.target
+", every);
+
+    let unit_path = get_config_path() + "/systemd/user";
+    let mut changed = write_file_if_changed(&format!("{}/{}", unit_path, SCHEDULE_SERVICE_NAME), &service)?;
+    changed |= write_file_if_changed(&format!("{}/{}", unit_path, SCHEDULE_TIMER_NAME), &timer)?;
+
+    if changed {
+        let _status = std::process::Command::new("systemctl")
+            .arg("--user")
+            .arg("daemon-reload")
+            .status()?;
+    }
+
+    println!("Scheduling localsetup to run every {} … ", every);
+    systemctl_user("enable", SCHEDULE_TIMER_NAME)?;
+    systemctl_user(if changed { "restart" } else { "start" }, SCHEDULE_TIMER_NAME)?;
+    println!("Scheduled runs log to the journal, see: journalctl --user -u {}", SCHEDULE_SERVICE_NAME);
+    Ok(())
+}
+
+fn schedule_disable() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Removing scheduled localsetup runs … ");
+    let unit_path = get_config_path() + "/systemd/user";
+    if !path_exists(&format!("{}/{}", unit_path, SCHEDULE_TIMER_NAME)) {
+        println!("No scheduled runs set up");
+        return Ok(());
+    }
+
+    systemctl_user("stop", SCHEDULE_TIMER_NAME)?;
+    systemctl_user("disable", SCHEDULE_TIMER_NAME)?;
+    std::fs::remove_file(format!("{}/{}", unit_path, SCHEDULE_TIMER_NAME))?;
+    let _ = std::fs::remove_file(format!("{}/{}", unit_path, SCHEDULE_SERVICE_NAME));
+
+    let _status = std::process::Command::new("systemctl")
+        .arg("--user")
+        .arg("daemon-reload")
+        .status()?;
+    println!("done");
+    Ok(())
+}
+
+fn schedule_status() -> Result<(), Box<dyn std::error::Error>> {
+    let properties = systemd_user_show(SCHEDULE_TIMER_NAME)?;
+    if properties.get("UnitFileState").is_none_or(|state| state != "enabled") {
+        println!("No scheduled runs set up");
+        return Ok(());
+    }
+
+    let _status = std::process::Command::new("systemctl")
+        .arg("--user")
+        .arg("status")
+        .arg("--no-pager")
+        .arg(SCHEDULE_TIMER_NAME)
+        .arg(SCHEDULE_SERVICE_NAME)
+        .status()?;
+    println!("\nFull log of scheduled runs: journalctl --user -u {}", SCHEDULE_SERVICE_NAME);
+    Ok(())
+}
+
+fn schedule(action: Option<String>, mut args: std::env::Args) -> Result<(), Box<dyn std::error::Error>> {
+    match action.as_deref() {
+        Some("enable") => {
+            let mut every = "1h".to_string();
+            while let Some(arg) = args.next() {
+                if arg == "--every" {
+                    if let Some(value) = args.next() {
+                        every = value;
+                    } else {
+                        return args_error("Missing argument <time> for --every.");
+                    }
+                } else {
+                    return args_error(&format!("Unknown argument '{}'", arg));
+                }
+            }
+            schedule_enable(&every)
+        }
+        Some("disable") | Some("status") => {
+            if args.next().is_some() {
+                return args_error("Too many arguments.");
+            }
+            if action.as_deref() == Some("disable") {
+                schedule_disable()
+            } else {
+                schedule_status()
+            }
+        }
+        Some(action) => args_error(&format!("Unknown schedule action '{}'", action)),
+        None => args_error("Missing argument enable, disable or status."),
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+struct RepositoryStatus {
+    source: String,
+    target: String,
+    exists: bool,
+    branch: Option<String>,
+    upstream: Option<String>,
+    modified: usize,
+    untracked: usize,
+    ahead: usize,
+    behind: usize,
+    origin_matches: Option<bool>,
+}
+
+fn repository_status(repository: &Repository, fetch: bool) -> Result<RepositoryStatus, Box<dyn std::error::Error>> {
+    let target = expand_home(&repository.target);
+    let mut status = RepositoryStatus {
+        source: repository.source.clone(),
+        target: repository.target.clone(),
+        exists: path_exists(&format!("{}/.git", target)),
+        ..Default::default()
+    };
+    if !status.exists {
+        return Ok(status);
+    }
+
+    let remote = repository_remote(repository);
+    if fetch {
+        std::process::Command::new("git")
+            .args(["fetch", "--quiet", remote])
+            .current_dir(&target)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::io::stderr())
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .status()?;
+    }
+
+    let url = remote_url(&target, remote)?;
+    status.origin_matches = Some(same_url(&url, &repository.source));
+
+    // The first line looks like "## main...origin/main [ahead 1, behind 2]" or "## HEAD (no branch)"
+    let output = git_output(&target, &["status", "--porcelain", "--branch"])?;
+    let mut lines = output.lines();
+    if let Some(header) = lines.next().and_then(|line| line.strip_prefix("## ")) {
+        let (branches, counts) = header.split_once(" [").unwrap_or((header, ""));
+        let (branch, upstream) = match branches.split_once("...") {
+            Some((branch, upstream)) => (branch, Some(upstream.to_owned())),
+            None => (branches, None),
+        };
+        if branch != "HEAD (no branch)" {
+            status.branch = Some(branch.strip_prefix("No commits yet on ").unwrap_or(branch).to_owned());
+        }
+        status.upstream = upstream;
+        for count in counts.trim_end_matches(']').split(", ") {
+            if let Some(ahead) = count.strip_prefix("ahead ") {
+                status.ahead = ahead.parse()?;
+            } else if let Some(behind) = count.strip_prefix("behind ") {
+                status.behind = behind.parse()?;
+            }
+        }
+    }
+    for line in lines {
+        if line.starts_with("??") {
+            status.untracked += 1;
+        } else {
+            status.modified += 1;
+        }
+    }
+
+    Ok(status)
+}
+
+fn status(mut args: std::env::Args) -> Result<(), Box<dyn std::error::Error>> {
+    let mut fetch = false;
+    let mut json = false;
+    for arg in args.by_ref() {
+        match arg.as_str() {
+            "--fetch" => fetch = true,
+            "--json" => json = true,
+            _ => return args_error(&format!("Unknown argument '{}'", arg)),
+        }
+    }
+
+    let config = read_config()?;
+    let setup = get_setup(&config)?;
+    let mut statuses = Vec::new();
+    for repository in &expand_repositories(&setup) {
+        statuses.push(repository_status(repository, fetch)?);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+        return Ok(());
+    }
+
+    let mut rows = vec![["Repository", "Branch", "Changes", "Ahead/Behind", "Origin"].map(String::from)];
+    for status in &statuses {
+        if !status.exists {
+            rows.push([status.target.clone(), "-".into(), "missing".into(), "-".into(), "-".into()]);
+            continue;
+        }
+        let changes = match (status.modified, status.untracked) {
+            (0, 0) => "clean".to_owned(),
+            (modified, untracked) => format!("{} modified, {} untracked", modified, untracked),
+        };
+        let ahead_behind = match status.upstream {
+            Some(_) => format!("+{} -{}", status.ahead, status.behind),
+            None => "no upstream".to_owned(),
+        };
+        let origin = if status.origin_matches == Some(true) { "ok" } else { "differs" };
+        rows.push([
+            status.target.clone(),
+            status.branch.clone().unwrap_or("(detached)".into()),
+            changes,
+            ahead_behind,
+            origin.to_owned(),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+
+    Ok(())
+}
+
 static HELP_MESSAGE: &str = "Usage:\n  \
                              localsetup                                    - Runs localsetup and ensures a previously given config is applied.\n  \
                              localsetup init [<config file or repository]  - Installs localsetup to $HOME/.local/bin/ and optionally sets config\n  \
                              localsetup config <config file or repository> - Sets config to a given file (local file or https://) or repository (git://)\n  \
+                             localsetup schedule enable [--every <time>]   - Runs localsetup regularly (default: every 1h) via a systemd user timer\n  \
+                             localsetup schedule disable|status            - Removes or shows the scheduled runs\n  \
+                             localsetup status [--fetch] [--json]          - Shows the state of all repositories (optionally after fetching)\n  \
                              localsetup help                               - Shows this message";
 
 fn help() -> Result<(), Box<dyn std::error::Error>> {
@@ -1207,12 +4064,12 @@ fn update_setupfile(setupfile: String) -> Result<(), Box<dyn std::error::Error>>
     config.source = Some(setupfile.clone());
 
     // Check that we can read the setupfile
-    get_setup(config.source.as_ref().unwrap())?;
+    get_setup(&config)?;
 
     // If we're still here, the check was successful
     write_config(config)?;
 
-    println!("Config file successfully set to {}", setupfile);
+    println!("Config file successfully set to {}", redact_url(&setupfile));
     Ok(())
 }
 
@@ -1268,6 +4125,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
+        if param == "status" {
+            return status(args);
+        }
+
+        if param == "schedule" {
+            let action = args.next();
+            return schedule(action, args);
+        }
+
         return args_error(&format!("Unknown command '{}'", param));
     }
 
@@ -1287,9 +4153,9 @@ fn localsetup() -> Result<(), Box<dyn std::error::Error>> {
 
     let config = read_config()?;
 
-    println!("Using config file at: {}", config.source.as_ref().unwrap());
+    println!("Using config file at: {}", redact_url(config.source.as_ref().unwrap()));
 
-    let setup = get_setup(config.source.as_ref().unwrap())?;
+    let setup = get_setup(&config)?;
 
     #[cfg(debug_assertions)]
     println!("{:#?}", setup);
@@ -1320,27 +4186,46 @@ fn localsetup() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     if setup.ssh.as_ref().map(|ssh| ssh.setup_ssh_key.unwrap_or(true)).unwrap_or(true) {
-        setup_ssh_key(setup.ssh.as_ref().map(|ssh| ssh.no_passphrase.unwrap_or(false)).unwrap_or(false))?;
+        let no_passphrase = setup.ssh.as_ref().map(|ssh| ssh.no_passphrase.unwrap_or(false)).unwrap_or(false);
+        // No need to wait for the user to copy the key if it gets registered anyway
+        let wait_for_user = setup.ssh.as_ref().is_none_or(|ssh| ssh.register.is_none());
+        for key in ssh_keys(setup.ssh.as_ref()) {
+            setup_ssh_key(&key, no_passphrase, wait_for_user)?;
+        }
+    }
+
+    if let Some(register) = setup.ssh.as_ref().and_then(|ssh| ssh.register.as_ref())
+        && let Err(err) = register_ssh_key(register, &primary_ssh_key_path(setup.ssh.as_ref())) {
+        println!("ERROR\nCould not register SSH key: {}", err);
+    }
+
+    setup_known_hosts(setup.ssh.as_ref())?;
+
+    // SSH hosts need to be set up before cloning any repositories through them
+    if let Some(ssh) = setup.ssh.as_ref() && (ssh.hosts.is_some() || ssh.add_keys_to_agent.is_some()) {
+        setup_ssh_config(ssh)?;
     }
 
+    let _ssh_agent = match setup.ssh.as_ref().and_then(|ssh| ssh.agent.as_ref()) {
+        Some(agent) => setup_ssh_agent(agent, &ssh_keys(setup.ssh.as_ref()))?,
+        None => None,
+    };
+
     if let Some(git) = setup.git.as_ref() {
         setup_git(&git)?;
+
+        if let Some(signing) = git.signing.as_ref() {
+            setup_git_signing(signing, git.email.as_ref(), &primary_ssh_key_path(setup.ssh.as_ref()))?;
+        }
     }
 
-    if let Some(repositories) = setup.repositories.as_ref() {
+    let repositories = expand_repositories(&setup);
+    let mut repository_failures = Vec::new();
+    if !repositories.is_empty() {
         println!("Setting up repositories …");
 
-        for repository in repositories {
-            let newly_setup = setup_repository(repository)?;
-
-            if !newly_setup {
-                if repository.synchronise.unwrap_or_default() {
-                    synchronise_repository(repository)?;
-                } else if repository.update.unwrap_or_default() {
-                    update_repository(repository)?;
-                }
-            }
-        }
+        let jobs = setup.repository_settings.as_ref().and_then(|settings| settings.jobs).unwrap_or(1);
+        repository_failures = setup_repositories(&repositories, jobs);
     }
 
     if let Some(xdg_user_dirs) = setup.xdg_user_dirs.as_ref() {
@@ -1351,6 +4236,10 @@ fn localsetup() -> Result<(), Box<dyn std::error::Error>> {
         ubuntu_specifics(ubuntu)?;
     }
 
+    if let Some(environment) = setup.environment.as_ref() {
+        setup_environment(environment)?;
+    }
+
     if let Some(rustup) = setup.rustup.as_ref() {
         setup_rustup(rustup)?;
     }
@@ -1367,5 +4256,215 @@ fn localsetup() -> Result<(), Box<dyn std::error::Error>> {
         setup_signal(signal)?;
     }
 
+    if let Some(shell) = setup.shell.as_ref() {
+        setup_shell(shell)?;
+    }
+
+    if let Some(systemd_user) = setup.systemd_user.as_ref() {
+        setup_systemd_user_units(systemd_user)?;
+    }
+
+    if let Some(gsettings) = setup.gsettings.as_ref() {
+        setup_gsettings(gsettings)?;
+    }
+
+    if let Some(dconf) = setup.dconf.as_ref() {
+        setup_dconf(dconf)?;
+    }
+
+    if let Some(kde) = setup.kde.as_ref() {
+        setup_kde(kde)?;
+    }
+
+    if let Some(default_apps) = setup.default_apps.as_ref() {
+        setup_default_apps(default_apps)?;
+    }
+
+    // Also runs without fonts in the Setupfile, so previously installed fonts get removed
+    setup_fonts(setup.fonts.as_deref().unwrap_or_default())?;
+
+    // Listed last, so they do not scroll away
+    if !repository_failures.is_empty() {
+        println!("The following repositories need manual attention:");
+        for (target, reason) in repository_failures {
+            println!("  {}: {}", target, reason);
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request received by `serve_forge`: method, path (with query) and body.
+    type ForgeRequest = (String, String, String);
+
+    /// Serves canned forge API responses: `pages` maps a request path (without query) to the pages it returns. All
+    /// received requests are recorded in the returned list.
+    fn serve_forge(pages: Vec<(&'static str, Vec<serde_json::Value>)>) -> (String, std::sync::Arc<std::sync::Mutex<Vec<ForgeRequest>>>) {
+        use std::io::{BufRead, BufReader, Read};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    if let Some((name, value)) = header.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap_or_default();
+                    }
+                    header.clear();
+                }
+                let mut request_body = vec![0; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+
+                let mut request_line = request_line.split_whitespace();
+                let method = request_line.next().unwrap_or_default();
+                let target = request_line.next().unwrap_or_default();
+                recorded.lock().unwrap().push((method.to_string(), target.to_string(), String::from_utf8_lossy(&request_body).to_string()));
+
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let page = query.split('&')
+                    .find_map(|parameter| parameter.strip_prefix("page="))
+                    .and_then(|page| page.parse::<usize>().ok())
+                    .unwrap_or(1);
+                let body = match pages.iter().find(|(known, _)| *known == path) {
+                    Some((_, pages)) if method == "GET" => pages.get(page - 1).cloned().unwrap_or(serde_json::json!([])).to_string(),
+                    _ => "[]".to_string(),
+                };
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            }
+        });
+        (base_url, requests)
+    }
+
+    fn github_repository(name: &str, archived: bool, fork: bool) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "ssh_url": format!("git@github.com:team/{}.git", name),
+            "clone_url": format!("https://github.com/team/{}.git", name),
+            "archived": archived,
+            "fork": fork,
+        })
+    }
+
+    #[test]
+    fn list_repository_set_from_forges() {
+        let first_github_page = (0..50).map(|i| github_repository(&format!("repo-{}", i), false, false)).collect();
+        let (base_url, _) = serve_forge(vec![
+            ("/orgs/team/repos", vec![
+                first_github_page,
+                serde_json::json!([
+                    github_repository("alpha", false, false),
+                    github_repository("archived", true, false),
+                    github_repository("forked", false, true),
+                    github_repository("legacy-tool", false, false),
+                    {"name": "no-url", "archived": false, "fork": false},
+                ]),
+            ]),
+            ("/api/v4/groups/team%2Fsub/projects", vec![serde_json::json!([
+                {"name": "Alpha Project", "path": "alpha", "ssh_url_to_repo": "git@gitlab.com:team/sub/alpha.git", "http_url_to_repo": "https://gitlab.com/team/sub/alpha.git", "archived": false},
+                {"name": "Beta", "path": "beta", "ssh_url_to_repo": "git@gitlab.com:team/sub/beta.git", "http_url_to_repo": "https://gitlab.com/team/sub/beta.git", "archived": false, "forked_from_project": {"id": 1}},
+            ])]),
+            ("/api/v1/users/someone/repos", vec![serde_json::json!([
+                github_repository("alpha", false, false),
+                github_repository("forked", false, true),
+                github_repository("archived", true, false),
+            ])]),
+        ]);
+
+        let github = RepositorySet {
+            forge: Forge::Github,
+            base_url: Some(base_url.clone()),
+            org: Some("team".to_string()),
+            exclude: Some(vec!["legacy-*".to_string()]),
+            ..Default::default()
+        };
+        let listing = list_repository_set(&github).unwrap();
+        assert_eq!(listing.len(), 51);
+        assert_eq!(listing[0], ("repo-0".to_string(), "git@github.com:team/repo-0.git".to_string()));
+        assert_eq!(listing[50], ("alpha".to_string(), "git@github.com:team/alpha.git".to_string()));
+
+        let gitlab = RepositorySet {
+            forge: Forge::Gitlab,
+            base_url: Some(base_url.clone()),
+            org: Some("team/sub".to_string()),
+            protocol: Some("https".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(list_repository_set(&gitlab).unwrap(), vec![("alpha".to_string(), "https://gitlab.com/team/sub/alpha.git".to_string())]);
+
+        let gitea = RepositorySet {
+            forge: Forge::Gitea,
+            base_url: Some(base_url),
+            user: Some("someone".to_string()),
+            include: Some(vec!["*".to_string()]),
+            forks: Some(true),
+            ..Default::default()
+        };
+        let names = list_repository_set(&gitea).unwrap().into_iter().map(|(name, _)| name).collect::<Vec<String>>();
+        assert_eq!(names, vec!["alpha", "forked"]);
+    }
+
+    fn forge_key(key: &str) -> serde_json::Value {
+        serde_json::json!({"id": 1, "key": key, "title": "some machine"})
+    }
+
+    /// Writes a public key and a token file to a temporary directory and returns the registration for them.
+    fn ssh_register(name: &str, base_url: &str) -> SshRegister {
+        let directory = std::env::temp_dir().join(format!("localsetup-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("id_ed25519.pub"), "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKey user@laptop\n").unwrap();
+        std::fs::write(directory.join("token"), "secret\n").unwrap();
+        SshRegister {
+            forge: Forge::Github,
+            base_url: Some(base_url.to_string()),
+            token_file: Some(directory.join("token").to_string_lossy().to_string()),
+            key: Some(directory.join("id_ed25519.pub").to_string_lossy().to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn register_ssh_key_already_registered() {
+        let first_page = (0..50).map(|i| forge_key(&format!("ssh-ed25519 AAAAOther{}", i))).collect();
+        let (base_url, requests) = serve_forge(vec![
+            ("/user/keys", vec![first_page, serde_json::json!([forge_key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKey")])]),
+        ]);
+
+        let register = ssh_register("registered", &base_url);
+        register_ssh_key(&register, "").unwrap();
+        let _ = std::fs::remove_dir_all(std::path::Path::new(register.key.as_ref().unwrap()).parent().unwrap());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|(method, _, _)| method == "GET"));
+        assert!(requests[1].1.contains("page=2"));
+    }
+
+    #[test]
+    fn register_ssh_key_missing() {
+        let (base_url, requests) = serve_forge(vec![
+            ("/user/keys", vec![serde_json::json!([forge_key("ssh-ed25519 AAAAOther")])]),
+        ]);
+
+        let register = ssh_register("missing", &base_url);
+        register_ssh_key(&register, "").unwrap();
+        let _ = std::fs::remove_dir_all(std::path::Path::new(register.key.as_ref().unwrap()).parent().unwrap());
+        let requests = requests.lock().unwrap();
+        let posts = requests.iter().filter(|(method, _, _)| method == "POST").collect::<Vec<_>>();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].1, "/user/keys");
+        let body: serde_json::Value = serde_json::from_str(&posts[0].2).unwrap();
+        assert_eq!(body["key"], "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKey");
+        assert_eq!(body["title"], format!("{} {}", get_hostname().unwrap(), get_date("%F").unwrap()));
+    }
+}